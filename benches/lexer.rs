@@ -0,0 +1,18 @@
+// Lexes a large generated program and reports throughput. Run with
+// `cargo bench --bench lexer`; time should grow linearly with LINES.
+use std::time::Instant;
+use compiler::lexer::Lexer;
+
+const LINES: usize = 200_000;
+
+fn main() {
+    let mut source = String::new();
+    for i in 0..LINES {
+        source.push_str(&format!("let v{} = &HFF & {} + \"strïng\" ' cömment\n", i, i));
+    }
+    let time = Instant::now();
+    let tokens = Lexer::new(source.clone()).collect::<Result<Vec<_>, _>>().unwrap().len();
+    let elapsed = time.elapsed();
+    println!("lexed {} lines ({} bytes, {} tokens) in {:?}", LINES, source.len(), tokens, elapsed);
+    println!("{:.1} MB/s", source.len() as f64 / elapsed.as_secs_f64() / 1_000_000.0);
+}
@@ -0,0 +1,131 @@
+// The parsed program. Statements remember the line and column they start at
+// so later stages can report errors and map code back to the source.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Xor,
+    And,
+    Or,
+    // Only made by the optimiser, there is no shift in the language
+    Shl,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+}
+
+impl BinaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add          => "+",
+            BinaryOp::Sub          => "-",
+            BinaryOp::Mul          => "*",
+            BinaryOp::Div          => "/",
+            BinaryOp::Mod          => "%",
+            BinaryOp::Xor          => "^",
+            BinaryOp::And          => "&",
+            BinaryOp::Or           => "|",
+            BinaryOp::Shl          => "<<",
+            BinaryOp::Equal        => "==",
+            BinaryOp::NotEqual     => "!=",
+            BinaryOp::Less         => "<",
+            BinaryOp::Greater      => ">",
+            BinaryOp::LessEqual    => "<=",
+            BinaryOp::GreaterEqual => ">=",
+        }
+    }
+
+    // Comparisons evaluate to -1 (true) or 0 (false)
+    pub fn is_relation(&self) -> bool {
+        matches!(self, BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::Less | BinaryOp::Greater | BinaryOp::LessEqual | BinaryOp::GreaterEqual)
+    }
+
+    // The comparison that holds exactly when this one doesn't
+    pub fn negated(&self) -> Option<BinaryOp> {
+        match self {
+            BinaryOp::Equal        => Some(BinaryOp::NotEqual),
+            BinaryOp::NotEqual     => Some(BinaryOp::Equal),
+            BinaryOp::Less         => Some(BinaryOp::GreaterEqual),
+            BinaryOp::Greater      => Some(BinaryOp::LessEqual),
+            BinaryOp::LessEqual    => Some(BinaryOp::Greater),
+            BinaryOp::GreaterEqual => Some(BinaryOp::Less),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Variable(String),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    // A function the host provides, which gives back a number
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    // Whether working it out calls the host, which may do something more
+    // than give back a number
+    pub fn calls(&self) -> bool {
+        match self {
+            Expr::Number(_) | Expr::Variable(_) => false,
+            Expr::Binary(_, left, right) => left.calls() || right.calls(),
+            Expr::Call(..) => true,
+        }
+    }
+
+    // Whether working it out might divide by zero, which stops the program.
+    // Only dividing by a constant other than zero is sure not to.
+    pub fn may_divide_by_zero(&self) -> bool {
+        match self {
+            Expr::Number(_) | Expr::Variable(_) => false,
+            Expr::Binary(op, left, right) => {
+                let divides = matches!(op, BinaryOp::Div | BinaryOp::Mod) && !matches!(**right, Expr::Number(divisor) if divisor != 0);
+                divides || left.may_divide_by_zero() || right.may_divide_by_zero()
+            }
+            Expr::Call(_, arguments) => arguments.iter().any(Expr::may_divide_by_zero),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StatementKind {
+    Let { name: String, value: Expr },
+    Assign { name: String, value: Expr },
+    If { condition: Expr, body: Vec<Statement> },
+    While { condition: Expr, body: Vec<Statement> },
+    Label(String),
+    Goto(String),
+    Gosub(String),
+    Return,
+    // Writes a number and a newline
+    Print(Expr),
+    // Reads a number into a variable
+    Input(String),
+    // Calls a host function for what it does, dropping what it gives back
+    Call { name: String, arguments: Vec<Expr> },
+    End,
+    Comment(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Statement>,
+    // Variables the host gives the program, which it uses without
+    // declaring them
+    pub globals: Vec<String>,
+}
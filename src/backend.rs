@@ -0,0 +1,93 @@
+use crate::ast::BinaryOp;
+use crate::ir::{BlockId, Cfg, Inst, Terminator, Value};
+
+// What a target has to be able to write for `emit` to lower a program to
+// it. Each call writes the code for one step in the target's own form, and
+// a backend keeps whatever it needs, like which register a temporary is in,
+// between calls. Blocks come in layout order, and a temporary is always
+// worked out in the block that reads it.
+pub trait Backend {
+    // Starts a block, which jumps and calls name by its id
+    fn label(&mut self, block: BlockId);
+    // Loads `src` and stores it in `dest`
+    fn copy(&mut self, dest: &Value, src: &Value);
+    // Works out `left op right` into `dest`, where a comparison gives -1
+    // (true) or 0 (false)
+    fn arithmetic(&mut self, op: BinaryOp, dest: &Value, left: &Value, right: &Value);
+    fn print(&mut self, value: &Value);
+    fn input(&mut self, dest: &Value);
+    fn comment(&mut self, text: &str);
+    // Says which statement the code that follows comes from, for targets
+    // that map their code back to the source
+    fn position(&mut self, _line: usize, _column: usize) {}
+    // Calls a host function with the arguments in order, keeping what it
+    // gives back in `dest` if there is one. Only targets that run inside
+    // another program can.
+    fn host(&mut self, _dest: Option<&Value>, name: &str, _arguments: &[Value]) {
+        panic!("Cannot call host function {} on this target", name);
+    }
+    fn jump(&mut self, target: BlockId);
+    // Jumps to `target` when `left op right` holds, and otherwise goes on
+    fn branch(&mut self, op: BinaryOp, left: &Value, right: &Value, target: BlockId);
+    // Calls `target`, coming back to whatever is written next. That goes on
+    // to `after`, for targets that can only come back to the start of a
+    // block.
+    fn call(&mut self, target: BlockId, after: BlockId);
+    // Comes back from the latest call, and fails if there isn't one
+    fn ret(&mut self);
+    // Ends the program from anywhere but the end of the code, where it ends
+    // by itself
+    fn halt(&mut self);
+}
+
+// Lowers every block of a program through a backend. Jumps to the block
+// laid out next are left out, and a branch that would jump over one goes
+// the other way on the opposite condition instead.
+pub fn emit(cfg: &Cfg, backend: &mut dyn Backend) {
+    for (index, &id) in cfg.order.iter().enumerate() {
+        let next = cfg.order.get(index + 1).copied();
+        let block = &cfg.blocks[id];
+        backend.label(id);
+        for inst in &block.insts {
+            match inst {
+                Inst::Copy { dest, src } => backend.copy(dest, src),
+                Inst::Binary { dest, op, left, right } => backend.arithmetic(*op, dest, left, right),
+                Inst::Print(value) => backend.print(value),
+                Inst::Input(dest) => backend.input(dest),
+                Inst::Call { dest, name, arguments } => backend.host(dest.as_ref(), name, arguments),
+                Inst::Comment(text) => backend.comment(text),
+                Inst::Position { line, column } => backend.position(*line, *column),
+            }
+        }
+        let jump = |backend: &mut dyn Backend, target: BlockId| {
+            if next != Some(target) {
+                backend.jump(target);
+            }
+        };
+        match &block.terminator {
+            Terminator::Jump(target) => jump(backend, *target),
+            Terminator::Branch { op, left, right, then, otherwise } if next == Some(*then) && *otherwise != *then => {
+                backend.branch(op.negated().unwrap(), left, right, *otherwise);
+            }
+            Terminator::Branch { op, left, right, then, otherwise } => {
+                backend.branch(*op, left, right, *then);
+                jump(backend, *otherwise);
+            }
+            Terminator::Call { target, next: after } => {
+                backend.call(*target, *after);
+                jump(backend, *after);
+            }
+            Terminator::Return => backend.ret(),
+            Terminator::Halt => if next.is_some() {
+                backend.halt();
+            },
+        }
+    }
+}
+
+// The symbol for a variable on the targets written for GNU as. Their
+// runtimes name everything basic_..., and the prefix keeps variables apart
+// from that.
+pub fn symbol(name: &str) -> String {
+    format!("var_{}", name)
+}
@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use crate::ast::BinaryOp;
+use crate::backend::{self, Backend};
+use crate::diagnostic::Diagnostic;
+use crate::emulator::RuntimeError;
+use crate::ir::{BlockId, Cfg, Value};
+
+// A compact bytecode for a stack machine, for running programs inside other
+// Rust programs with `vm`. Values are 32-bit words, as on the other targets
+// besides the register machine. Every variable has a slot, temporaries
+// that are never live at once share one, and an instruction pushes values
+// on the stack, works on the top of it or pops it into a slot.
+//
+// A file is, with every number little-endian:
+//
+// - the header: MAGIC, the format VERSION as a u16, and how many slots the
+//   program needs as a u16
+// - the constant pool: a u32 count, then each constant as an i32
+// - the code: a u32 length in bytes, then the instructions
+// - the symbol table: a u16 count, then each variable's slot as a u16 and
+//   its name as a u8 length and UTF-8
+// - the function table: a u16 count, then how many arguments each host
+//   function takes as a u8 and its name as a u8 length and UTF-8
+//
+// An instruction is an opcode byte, then a u16 constant, slot or function,
+// a u32 code offset, or nothing.
+pub const MAGIC: &[u8; 4] = b"BASb";
+pub const VERSION: u16 = 2;
+
+const HALT: u8 = 0x00;
+// Pushes a constant
+const PUSH: u8 = 0x01;
+// Pushes a slot
+const LOAD: u8 = 0x02;
+// Pops into a slot
+const STORE: u8 = 0x03;
+// Pops and writes a number and a newline
+const PRINT: u8 = 0x04;
+// Reads a number and pushes it
+const INPUT: u8 = 0x05;
+const JUMP: u8 = 0x06;
+// Jumps and keeps where to come back to
+const CALL: u8 = 0x07;
+const RETURN: u8 = 0x08;
+// Drops the top of the stack
+const POP: u8 = 0x09;
+// Pops a host function's arguments, last first, calls it and pushes what it
+// gives back
+const HOST: u8 = 0x0a;
+// An operator pops the right side and then the left and pushes the result,
+// where a comparison gives -1 (true) or 0 (false)
+const OPERATOR: u8 = 0x10;
+// A branch pops the two sides of a comparison and jumps when it holds
+const BRANCH: u8 = 0x30;
+
+// Operators in opcode order, with their names in listings
+const OPERATORS: [(BinaryOp, &str); 15] = [
+    (BinaryOp::Add, "add"),
+    (BinaryOp::Sub, "sub"),
+    (BinaryOp::Mul, "mul"),
+    (BinaryOp::Div, "div"),
+    (BinaryOp::Mod, "mod"),
+    (BinaryOp::Xor, "xor"),
+    (BinaryOp::And, "and"),
+    (BinaryOp::Or, "or"),
+    (BinaryOp::Shl, "shl"),
+    (BinaryOp::Equal, "eq"),
+    (BinaryOp::NotEqual, "ne"),
+    (BinaryOp::Less, "lt"),
+    (BinaryOp::Greater, "gt"),
+    (BinaryOp::LessEqual, "le"),
+    (BinaryOp::GreaterEqual, "ge"),
+];
+
+fn operator_index(op: BinaryOp) -> u8 {
+    OPERATORS.iter().position(|(other, _)| *other == op).unwrap() as u8
+}
+
+// An instruction as the VM runs it, with jumps going to the index of an
+// instruction rather than its offset in the code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    Halt,
+    Push(i32),
+    Load(usize),
+    Store(usize),
+    Print,
+    Input,
+    Jump(usize),
+    Call(usize),
+    Return,
+    Pop,
+    // A host function and how many arguments it pops
+    Host(usize, usize),
+    Operator(BinaryOp),
+    Branch(BinaryOp, usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub slots: u16,
+    pub constants: Vec<i32>,
+    pub code: Vec<u8>,
+    // Each variable and its slot
+    pub symbols: Vec<(String, u16)>,
+    // Each host function the program calls and how many arguments it takes
+    pub functions: Vec<(String, u8)>,
+}
+
+// Fails on a program that doesn't fit the file format: one with more slots,
+// constants or host functions than a u16 can number, or a name longer than
+// a u8 can give the length of
+pub fn generate(cfg: &Cfg) -> Result<Chunk, Diagnostic> {
+    let mut symbols: Vec<(String, u16)> = Vec::new();
+    for (slot, variable) in cfg.variables.iter().enumerate() {
+        if variable.name.len() > u8::MAX as usize {
+            let message = format!("Variable names in bytecode can be at most {} bytes long", u8::MAX);
+            return Err(Diagnostic::new(message, variable.line, variable.column));
+        }
+        if slot >= u16::MAX as usize {
+            let message = format!("Bytecode has room for {} variables", u16::MAX);
+            return Err(Diagnostic::new(message, variable.line, variable.column));
+        }
+        symbols.push((variable.name.clone(), slot as u16));
+    }
+    let (temps, count) = cfg.temp_slots();
+    let slots = symbols.len() + count;
+    if slots > u16::MAX as usize {
+        let message = format!("The program needs {} slots for its variables and temporaries, and bytecode has room for {}", slots, u16::MAX);
+        return Err(Diagnostic::new(message, 0, 0));
+    }
+    let mut bytecode = Bytecode {
+        code: Vec::new(),
+        constants: Vec::new(),
+        indices: HashMap::new(),
+        slots: symbols.iter().map(|(name, slot)| (name.clone(), *slot)).collect(),
+        temps,
+        variables: symbols.len(),
+        functions: Vec::new(),
+        blocks: HashMap::new(),
+        fixups: Vec::new(),
+        position: (0, 0),
+        error: None,
+    };
+    backend::emit(cfg, &mut bytecode);
+    if let Some(error) = bytecode.error {
+        return Err(error);
+    }
+    bytecode.code.push(HALT);
+    if u32::try_from(bytecode.code.len()).is_err() {
+        return Err(Diagnostic::new(format!("Bytecode can be at most {} bytes long", u32::MAX), 0, 0));
+    }
+    for (at, block) in &bytecode.fixups {
+        let offset = bytecode.blocks[block];
+        bytecode.code[*at..*at + 4].copy_from_slice(&offset.to_le_bytes());
+    }
+    Ok(Chunk { slots: slots as u16, constants: bytecode.constants, code: bytecode.code, symbols, functions: bytecode.functions })
+}
+
+struct Bytecode {
+    code: Vec<u8>,
+    constants: Vec<i32>,
+    // Where each constant is in the pool
+    indices: HashMap<i32, usize>,
+    slots: HashMap<String, u16>,
+    // Temporaries get the slots after the variables, numbered as in
+    // `Cfg::temp_slots`
+    temps: HashMap<usize, usize>,
+    variables: usize,
+    functions: Vec<(String, u8)>,
+    // Where each block starts, and the jumps waiting to be pointed at one
+    blocks: HashMap<BlockId, u32>,
+    fixups: Vec<(usize, BlockId)>,
+    // The statement being lowered, and the first thing in the program that
+    // doesn't fit the file format
+    position: (usize, usize),
+    error: Option<Diagnostic>,
+}
+
+impl Bytecode {
+    fn slot(&self, value: &Value) -> u16 {
+        match value {
+            Value::Var(name) => self.slots[name],
+            Value::Temp(temp) => (self.variables + self.temps[temp]) as u16,
+            Value::Const(_) => panic!("Cannot assign to a constant"),
+        }
+    }
+
+    // Keeps the first error, at the statement being lowered
+    fn fail(&mut self, message: String) {
+        if self.error.is_none() {
+            self.error = Some(Diagnostic::new(message, self.position.0, self.position.1));
+        }
+    }
+
+    fn push(&mut self, value: &Value) {
+        match value {
+            Value::Const(value) => {
+                let value = *value as i32;
+                let index = *self.indices.entry(value).or_insert_with(|| {
+                    self.constants.push(value);
+                    self.constants.len() - 1
+                });
+                if index > u16::MAX as usize {
+                    self.fail(format!("Bytecode has room for {} different numbers", u16::MAX as usize + 1));
+                }
+                self.code.push(PUSH);
+                self.code.extend((index as u16).to_le_bytes());
+            }
+            _ => {
+                let slot = self.slot(value);
+                self.code.push(LOAD);
+                self.code.extend(slot.to_le_bytes());
+            }
+        }
+    }
+
+    fn store(&mut self, dest: &Value) {
+        let slot = self.slot(dest);
+        self.code.push(STORE);
+        self.code.extend(slot.to_le_bytes());
+    }
+
+    // Writes a jump, call or branch to a block, whose offset is filled in
+    // once every block has one
+    fn jump_to(&mut self, opcode: u8, target: BlockId) {
+        self.code.push(opcode);
+        self.fixups.push((self.code.len(), target));
+        self.code.extend([0; 4]);
+    }
+}
+
+impl Backend for Bytecode {
+    fn label(&mut self, block: BlockId) {
+        self.blocks.insert(block, self.code.len() as u32);
+    }
+
+    fn copy(&mut self, dest: &Value, src: &Value) {
+        self.push(src);
+        self.store(dest);
+    }
+
+    fn arithmetic(&mut self, op: BinaryOp, dest: &Value, left: &Value, right: &Value) {
+        self.push(left);
+        self.push(right);
+        self.code.push(OPERATOR + operator_index(op));
+        self.store(dest);
+    }
+
+    fn print(&mut self, value: &Value) {
+        self.push(value);
+        self.code.push(PRINT);
+    }
+
+    fn input(&mut self, dest: &Value) {
+        self.code.push(INPUT);
+        self.store(dest);
+    }
+
+    fn host(&mut self, dest: Option<&Value>, name: &str, arguments: &[Value]) {
+        for argument in arguments {
+            self.push(argument);
+        }
+        if name.len() > u8::MAX as usize {
+            self.fail(format!("Function names in bytecode can be at most {} bytes long", u8::MAX));
+        }
+        if arguments.len() > u8::MAX as usize {
+            self.fail(format!("A function in bytecode takes at most {} arguments", u8::MAX));
+        }
+        let index = self.functions.iter().position(|(other, _)| other == name).unwrap_or_else(|| {
+            self.functions.push((name.to_string(), arguments.len() as u8));
+            self.functions.len() - 1
+        });
+        if index >= u16::MAX as usize {
+            self.fail(format!("Bytecode has room for {} host functions", u16::MAX));
+        }
+        self.code.push(HOST);
+        self.code.extend((index as u16).to_le_bytes());
+        match dest {
+            Some(dest) => self.store(dest),
+            None => self.code.push(POP),
+        }
+    }
+
+    // Comments are left out of the bytecode
+    fn comment(&mut self, _text: &str) {}
+
+    fn position(&mut self, line: usize, column: usize) {
+        self.position = (line, column);
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.jump_to(JUMP, target);
+    }
+
+    fn branch(&mut self, op: BinaryOp, left: &Value, right: &Value, target: BlockId) {
+        self.push(left);
+        self.push(right);
+        self.jump_to(BRANCH + operator_index(op), target);
+    }
+
+    fn call(&mut self, target: BlockId, _after: BlockId) {
+        self.jump_to(CALL, target);
+    }
+
+    fn ret(&mut self) {
+        self.code.push(RETURN);
+    }
+
+    fn halt(&mut self) {
+        self.code.push(HALT);
+    }
+}
+
+// Checks every instruction has the values it pops, and that the stack is
+// empty wherever the program goes somewhere else and wherever it lands, so
+// the VM never runs out
+fn check_stack(instructions: &[(usize, Instruction)]) -> Result<(), RuntimeError> {
+    let mut landings = std::collections::HashSet::new();
+    for (index, (_, instruction)) in instructions.iter().enumerate() {
+        match instruction {
+            Instruction::Jump(target) | Instruction::Branch(_, target) => { landings.insert(*target); }
+            Instruction::Call(target) => {
+                landings.insert(*target);
+                landings.insert(index + 1);
+            }
+            _ => {}
+        }
+    }
+    let mut depth = 0;
+    for (index, (at, instruction)) in instructions.iter().enumerate() {
+        let error = |message: &str| Err(RuntimeError { message: message.to_string(), address: *at });
+        if landings.contains(&index) && depth != 0 {
+            return error("A jump lands where the stack is not empty");
+        }
+        let (pops, pushes) = match instruction {
+            Instruction::Push(_) | Instruction::Load(_) | Instruction::Input => (0, 1),
+            Instruction::Store(_) | Instruction::Print | Instruction::Pop => (1, 0),
+            Instruction::Host(_, arguments) => (*arguments, 1),
+            Instruction::Operator(_) => (2, 1),
+            Instruction::Branch(..) => (2, 0),
+            _ => (0, 0),
+        };
+        if depth < pops {
+            return error("Pops more than is on the stack");
+        }
+        depth = depth - pops + pushes;
+        let leaves = matches!(instruction, Instruction::Jump(_) | Instruction::Branch(..) | Instruction::Call(_) | Instruction::Return | Instruction::Halt);
+        if leaves && depth != 0 {
+            return error("Leaves values on the stack when it jumps");
+        }
+    }
+    Ok(())
+}
+
+// Reads the parts of a file in order, failing at the offset where one is
+// missing or wrong
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize, what: &str) -> Result<&'a [u8], RuntimeError> {
+        let bytes = self.bytes.get(self.offset..self.offset + count)
+            .ok_or_else(|| RuntimeError { message: format!("The file ends in the middle of {}", what), address: self.offset })?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, RuntimeError> {
+        Ok(u16::from_le_bytes(self.take(2, what)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, RuntimeError> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    // The name of a variable or function, which has to be UTF-8
+    fn name(&mut self, length: usize, what: &str, of: &str) -> Result<String, RuntimeError> {
+        let at = self.offset;
+        String::from_utf8(self.take(length, what)?.to_vec())
+            .map_err(|_| RuntimeError { message: format!("A {}'s name is not UTF-8", of), address: at })
+    }
+}
+
+impl Chunk {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(self.slots.to_le_bytes());
+        bytes.extend((self.constants.len() as u32).to_le_bytes());
+        for constant in &self.constants {
+            bytes.extend(constant.to_le_bytes());
+        }
+        bytes.extend((self.code.len() as u32).to_le_bytes());
+        bytes.extend(&self.code);
+        bytes.extend((self.symbols.len() as u16).to_le_bytes());
+        for (name, slot) in &self.symbols {
+            bytes.extend(slot.to_le_bytes());
+            bytes.push(name.len() as u8);
+            bytes.extend(name.as_bytes());
+        }
+        bytes.extend((self.functions.len() as u16).to_le_bytes());
+        for (name, arguments) in &self.functions {
+            bytes.push(*arguments);
+            bytes.push(name.len() as u8);
+            bytes.extend(name.as_bytes());
+        }
+        bytes
+    }
+
+    // Reads a file back, checking the code makes sense so the VM doesn't
+    // have to
+    pub fn decode(bytes: &[u8]) -> Result<Chunk, RuntimeError> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(4, "the header")? != MAGIC {
+            return Err(RuntimeError { message: "Not a bytecode file".to_string(), address: 0 });
+        }
+        let version = reader.u16("the header")?;
+        if version != VERSION {
+            return Err(RuntimeError { message: format!("Cannot read version {} of the bytecode", version), address: 4 });
+        }
+        let slots = reader.u16("the header")?;
+        let count = reader.u32("the constant pool")? as usize;
+        let mut constants = Vec::new();
+        for _ in 0..count {
+            constants.push(i32::from_le_bytes(reader.take(4, "the constant pool")?.try_into().unwrap()));
+        }
+        let length = reader.u32("the code")? as usize;
+        let code = reader.take(length, "the code")?.to_vec();
+        let count = reader.u16("the symbol table")?;
+        let mut symbols = Vec::new();
+        for _ in 0..count {
+            let slot = reader.u16("the symbol table")?;
+            let length = reader.take(1, "the symbol table")?[0] as usize;
+            let at = reader.offset;
+            let name = reader.name(length, "the symbol table", "variable")?;
+            if slot >= slots {
+                return Err(RuntimeError { message: format!("Variable {} is in slot {} of {}", name, slot, slots), address: at });
+            }
+            symbols.push((name, slot));
+        }
+        let count = reader.u16("the function table")?;
+        let mut functions = Vec::new();
+        for _ in 0..count {
+            let arguments = reader.take(1, "the function table")?[0];
+            let length = reader.take(1, "the function table")?[0] as usize;
+            functions.push((reader.name(length, "the function table", "function")?, arguments));
+        }
+        if reader.offset != bytes.len() {
+            return Err(RuntimeError { message: "The file goes on after the function table".to_string(), address: reader.offset });
+        }
+        let chunk = Chunk { slots, constants, code, symbols, functions };
+        chunk.instructions()?;
+        Ok(chunk)
+    }
+
+    // Decodes the code, with the offset each instruction starts at
+    pub fn instructions(&self) -> Result<Vec<(usize, Instruction)>, RuntimeError> {
+        let mut decoded = Vec::new();
+        // Jumps are pointed at instructions once they have all been found
+        let mut targets = Vec::new();
+        let mut reader = Reader { bytes: &self.code, offset: 0 };
+        while reader.offset < self.code.len() {
+            let at = reader.offset;
+            let error = |message: String| RuntimeError { message, address: at };
+            let opcode = reader.take(1, "an instruction")?[0];
+            let instruction = match opcode {
+                HALT => Instruction::Halt,
+                PUSH => {
+                    let index = reader.u16("an instruction")?;
+                    let value = self.constants.get(index as usize)
+                        .ok_or_else(|| error(format!("There is no constant {}", index)))?;
+                    Instruction::Push(*value)
+                }
+                LOAD | STORE => {
+                    let slot = reader.u16("an instruction")?;
+                    if slot >= self.slots {
+                        return Err(error(format!("There is no slot {}", slot)));
+                    }
+                    if opcode == LOAD { Instruction::Load(slot as usize) } else { Instruction::Store(slot as usize) }
+                }
+                PRINT => Instruction::Print,
+                INPUT => Instruction::Input,
+                RETURN => Instruction::Return,
+                POP => Instruction::Pop,
+                HOST => {
+                    let index = reader.u16("an instruction")?;
+                    let (_, arguments) = self.functions.get(index as usize)
+                        .ok_or_else(|| error(format!("There is no function {}", index)))?;
+                    Instruction::Host(index as usize, *arguments as usize)
+                }
+                JUMP | CALL => {
+                    targets.push((decoded.len(), reader.u32("an instruction")? as usize));
+                    if opcode == JUMP { Instruction::Jump(0) } else { Instruction::Call(0) }
+                }
+                _ if (OPERATOR..OPERATOR + OPERATORS.len() as u8).contains(&opcode) => {
+                    Instruction::Operator(OPERATORS[(opcode - OPERATOR) as usize].0)
+                }
+                _ if (BRANCH..BRANCH + OPERATORS.len() as u8).contains(&opcode) && OPERATORS[(opcode - BRANCH) as usize].0.is_relation() => {
+                    targets.push((decoded.len(), reader.u32("an instruction")? as usize));
+                    Instruction::Branch(OPERATORS[(opcode - BRANCH) as usize].0, 0)
+                }
+                _ => return Err(error(format!("Unknown opcode {:#04x}", opcode))),
+            };
+            decoded.push((at, instruction));
+        }
+        let starts: HashMap<usize, usize> = decoded.iter().enumerate().map(|(index, (at, _))| (*at, index)).collect();
+        for (index, offset) in targets {
+            let target = starts.get(&offset).copied()
+                .ok_or_else(|| RuntimeError { message: format!("Jumps into the middle of an instruction at {}", offset), address: decoded[index].0 })?;
+            decoded[index].1 = match decoded[index].1 {
+                Instruction::Jump(_) => Instruction::Jump(target),
+                Instruction::Call(_) => Instruction::Call(target),
+                Instruction::Branch(op, _) => Instruction::Branch(op, target),
+                instruction => instruction,
+            };
+        }
+        check_stack(&decoded)?;
+        Ok(decoded)
+    }
+
+    // A listing of the code, one instruction a line with its offset, and
+    // variables and jump targets by name
+    pub fn disassemble(&self) -> Result<String, RuntimeError> {
+        let instructions = self.instructions()?;
+        let names: HashMap<usize, &str> = self.symbols.iter().map(|(name, slot)| (*slot as usize, name.as_str())).collect();
+        let slot = |slot: usize| names.get(&slot).map_or_else(|| format!("${}", slot), |name| name.to_string());
+        let offset = |index: usize| instructions[index].0;
+        let mut listing = String::new();
+        for (at, instruction) in &instructions {
+            let text = match instruction {
+                Instruction::Halt => String::from("halt"),
+                Instruction::Push(value) => format!("push {}", value),
+                Instruction::Load(index) => format!("load {}", slot(*index)),
+                Instruction::Store(index) => format!("store {}", slot(*index)),
+                Instruction::Print => String::from("print"),
+                Instruction::Input => String::from("input"),
+                Instruction::Jump(target) => format!("jump {}", offset(*target)),
+                Instruction::Call(target) => format!("call {}", offset(*target)),
+                Instruction::Return => String::from("return"),
+                Instruction::Pop => String::from("pop"),
+                Instruction::Host(index, _) => format!("host {}", self.functions[*index].0),
+                Instruction::Operator(op) => OPERATORS[operator_index(*op) as usize].1.to_string(),
+                Instruction::Branch(op, target) => format!("j{} {}", OPERATORS[operator_index(*op) as usize].1, offset(*target)),
+            };
+            listing.push_str(&format!("{:5}  {}\n", at, text));
+        }
+        Ok(listing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile, Options, Target};
+
+    fn chunk(source: &str) -> Result<Chunk, Vec<Diagnostic>> {
+        let output = compile(source, &Options { target: Target::Bytecode, ..Options::default() })?;
+        Ok(Chunk::decode(&output.binary.unwrap()).unwrap())
+    }
+
+    #[test]
+    fn temporaries_share_slots() {
+        let source: String = (0..1000).map(|n| format!("print x * {} + x\n", n)).collect();
+        assert_eq!(chunk(&format!("let x = 1\n{}", source)).unwrap().slots, 3);
+    }
+
+    #[test]
+    fn programs_too_big_for_the_format_are_reported() {
+        let source: String = (0..70_000).map(|n| format!("print x + {}\n", n)).collect();
+        let diagnostics = chunk(&format!("let x = 1\n{}", source)).unwrap_err();
+        assert_eq!(diagnostics, [Diagnostic::new("Bytecode has room for 65536 different numbers".to_string(), 65538, 1)]);
+        let diagnostics = chunk(&format!("let {} = 1\n", "a".repeat(256))).unwrap_err();
+        assert_eq!(diagnostics, [Diagnostic::new("Variable names in bytecode can be at most 255 bytes long".to_string(), 1, 1)]);
+        assert!(chunk(&format!("let {} = 1\n", "a".repeat(255))).is_ok());
+    }
+}
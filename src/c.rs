@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+use crate::ast::{BinaryOp, Expr, Program, Statement, StatementKind};
+
+// Translates a parsed program to C99, keeping its shape: `if` and `while`
+// stay as they are, labels become C labels and `goto` stays a `goto`. A
+// `gosub` pushes where to come back to and jumps, and `return` pops that
+// and jumps back through a switch at the end of main. Variables are 32-bit
+// globals that start at zero, and arithmetic wraps around and fails on
+// division by zero the way it does on the emulator.
+pub fn generate(program: &Program) -> String {
+    let mut c = C { lines: Vec::new(), indent: 1, gosubs: 0, referenced: HashSet::new(), returns: false };
+    c.scan(&program.statements);
+    c.block(&program.statements);
+    let mut source = String::from(RUNTIME);
+    let mut variables = Vec::new();
+    declared(&program.statements, &mut variables);
+    if !variables.is_empty() {
+        source.push('\n');
+        for name in &variables {
+            source.push_str(&format!("static int32_t {};\n", variable(name)));
+        }
+    }
+    source.push_str("\nint main(void) {\n");
+    for line in &c.lines {
+        source.push_str(line);
+        source.push('\n');
+    }
+    source.push_str("    return 0;\n");
+    if c.returns {
+        source.push_str("gosub_return:\n");
+        source.push_str("    if (depth == 0) fail(\"Return without a call\");\n");
+        source.push_str("    switch (gosubs[--depth]) {\n");
+        for gosub in 0..c.gosubs {
+            source.push_str(&format!("    case {}: goto gosub_{};\n", gosub, gosub));
+        }
+        source.push_str("    }\n");
+        source.push_str("    return 0;\n");
+    }
+    source.push_str("}\n");
+    source
+}
+
+// Names get a prefix so they can't clash with C keywords or the runtime
+fn variable(name: &str) -> String {
+    format!("var_{}", name)
+}
+
+fn label(name: &str) -> String {
+    format!("label_{}", name)
+}
+
+fn declared(statements: &[Statement], variables: &mut Vec<String>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Let { name, .. } => variables.push(name.clone()),
+            StatementKind::If { body, .. } | StatementKind::While { body, .. } => declared(body, variables),
+            _ => {}
+        }
+    }
+}
+
+fn expression(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(value) if *value as i32 == i32::MIN => String::from("INT32_MIN"),
+        Expr::Number(value) => (*value as i32).to_string(),
+        Expr::Variable(name) => variable(name),
+        Expr::Call(..) => panic!("C cannot call the host"),
+        Expr::Binary(op, left, right) => {
+            let (left, right) = (expression(left), expression(right));
+            match op {
+                BinaryOp::Add => format!("add({}, {})", left, right),
+                BinaryOp::Sub => format!("sub({}, {})", left, right),
+                BinaryOp::Mul => format!("mul({}, {})", left, right),
+                BinaryOp::Div => format!("divide({}, {})", left, right),
+                BinaryOp::Mod => format!("modulo({}, {})", left, right),
+                BinaryOp::Shl => format!("shl({}, {})", left, right),
+                BinaryOp::Xor | BinaryOp::And | BinaryOp::Or => format!("({} {} {})", left, op.symbol(), right),
+                // A comparison gives -1 (true) or 0 (false), like
+                // traditional BASIC
+                _ => format!("-({} {} {})", left, op.symbol(), right),
+            }
+        }
+    }
+}
+
+// The test of an `if` or `while`, which needs no -1 when it is a comparison
+fn condition(expr: &Expr) -> String {
+    match expr {
+        Expr::Binary(op, left, right) if op.is_relation() => format!("{} {} {}", expression(left), op.symbol(), expression(right)),
+        _ => expression(expr),
+    }
+}
+
+struct C {
+    lines: Vec<String>,
+    indent: usize,
+    // How many gosubs have been written so far, which numbers the places
+    // they come back to
+    gosubs: usize,
+    // Labels something jumps to, since C warns about the rest
+    referenced: HashSet<String>,
+    // Whether there is a `return`, and so anywhere to come back to
+    returns: bool,
+}
+
+impl C {
+    fn scan(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Goto(target) | StatementKind::Gosub(target) => { self.referenced.insert(target.clone()); }
+                StatementKind::Return => self.returns = true,
+                StatementKind::If { body, .. } | StatementKind::While { body, .. } => self.scan(body),
+                _ => {}
+            }
+        }
+    }
+
+    fn line(&mut self, line: String) {
+        self.lines.push(format!("{}{}", "    ".repeat(self.indent), line));
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { name, value } | StatementKind::Assign { name, value } => {
+                self.line(format!("{} = {};", variable(name), expression(value)));
+            }
+            StatementKind::If { condition: test, body } => {
+                self.line(format!("if ({}) {{", condition(test)));
+                self.indent += 1;
+                self.block(body);
+                self.indent -= 1;
+                self.line("}".to_string());
+            }
+            StatementKind::While { condition: test, body } => {
+                self.line(format!("while ({}) {{", condition(test)));
+                self.indent += 1;
+                self.block(body);
+                self.indent -= 1;
+                self.line("}".to_string());
+            }
+            // Labels sit at the start of the line, and the empty statement
+            // lets one end a block
+            StatementKind::Label(name) => if self.referenced.contains(name) {
+                self.lines.push(format!("{}:;", label(name)));
+            },
+            StatementKind::Goto(target) => self.line(format!("goto {};", label(target))),
+            StatementKind::Gosub(target) if self.returns => {
+                self.line(format!("gosub({});", self.gosubs));
+                self.line(format!("goto {};", label(target)));
+                self.lines.push(format!("gosub_{}:;", self.gosubs));
+                self.gosubs += 1;
+            }
+            // Nothing can ever come back
+            StatementKind::Gosub(target) => self.line(format!("goto {};", label(target))),
+            StatementKind::Return => self.line("goto gosub_return;".to_string()),
+            StatementKind::Print(value) => self.line(format!("print({});", expression(value))),
+            StatementKind::Input(name) => self.line(format!("{} = input();", variable(name))),
+            StatementKind::Call { .. } => panic!("C cannot call the host"),
+            StatementKind::End => self.line("return 0;".to_string()),
+            StatementKind::Comment(text) => self.line(format!("/* {} */", text.replace("*/", "* /"))),
+        }
+    }
+}
+
+// Arithmetic is done unsigned so overflow wraps instead of being undefined.
+// input reads numbers the way the emulator does.
+const RUNTIME: &str = r#"#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static void fail(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "runtime error: %s\n", message);
+    exit(1);
+}
+
+static inline int32_t add(int32_t a, int32_t b) { return (int32_t)((uint32_t)a + (uint32_t)b); }
+static inline int32_t sub(int32_t a, int32_t b) { return (int32_t)((uint32_t)a - (uint32_t)b); }
+static inline int32_t mul(int32_t a, int32_t b) { return (int32_t)((uint32_t)a * (uint32_t)b); }
+static inline int32_t shl(int32_t a, int32_t b) { return (int32_t)((uint32_t)a << (b & 31)); }
+
+static inline int32_t divide(int32_t a, int32_t b) {
+    if (b == 0) fail("Division by zero");
+    return b == -1 ? sub(0, a) : a / b;
+}
+
+static inline int32_t modulo(int32_t a, int32_t b) {
+    if (b == 0) fail("Division by zero");
+    return b == -1 ? 0 : a % b;
+}
+
+static inline void print(int32_t value) {
+    printf("%" PRId32 "\n", value);
+}
+
+static inline int32_t input(void) {
+    int c, negative = 0;
+    uint32_t value = 0;
+    while ((c = getchar()) != EOF && (c < '0' || c > '9')) negative = c == '-';
+    if (c == EOF) return 0;
+    do value = value * 10 + (uint32_t)(c - '0'); while ((c = getchar()) >= '0' && c <= '9');
+    return (int32_t)(negative ? 0 - value : value);
+}
+
+#define GOSUB_DEPTH 65536
+static int gosubs[GOSUB_DEPTH];
+static int depth;
+
+static inline void gosub(int from) {
+    if (depth == GOSUB_DEPTH) fail("Too many nested gosubs");
+    gosubs[depth++] = from;
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use crate::{compile, Options, Target};
+
+    // The lines of main, without the runtime and the declarations
+    fn main(source: &str) -> Vec<String> {
+        let options = Options { target: Target::C, ..Options::default() };
+        let c = compile(source, &options).unwrap().assembly;
+        c.lines().skip_while(|line| *line != "int main(void) {").skip(1).map(String::from).collect()
+    }
+
+    #[test]
+    fn the_shape_of_the_program_is_kept() {
+        let main = main("let a = 1\nwhile a < 5 do\nif a == 3 then\ngoto done\nend if\na = a + 1\nend while\nlabel done\nlabel unused\n");
+        assert_eq!(main, [
+            "    var_a = 1;",
+            "    while (var_a < 5) {",
+            "        if (var_a == 3) {",
+            "            goto label_done;",
+            "        }",
+            "        var_a = add(var_a, 1);",
+            "    }",
+            "label_done:;",
+            "    return 0;",
+            "}",
+        ]);
+    }
+
+    #[test]
+    fn gosubs_come_back_through_a_switch() {
+        let main = main("gosub sub\nend\nlabel sub\nprint 1\nreturn\n");
+        assert_eq!(main[..3], ["    gosub(0);", "    goto label_sub;", "gosub_0:;"]);
+        assert!(main.contains(&String::from("    case 0: goto gosub_0;")));
+        assert!(main.contains(&String::from("    if (depth == 0) fail(\"Return without a call\");")));
+    }
+
+    #[test]
+    fn a_gosub_with_no_return_is_a_goto() {
+        let main = main("gosub sub\nlabel sub\nend\n");
+        assert_eq!(main[0], "    goto label_sub;");
+        assert!(!main.iter().any(|line| line.contains("gosub_return")));
+    }
+
+    #[test]
+    fn arithmetic_wraps_and_comparisons_are_minus_one() {
+        let main = main("let a = 0 - 1\nlet b = a / 7 % 3\nlet c = a < b\nprint c\n");
+        assert_eq!(main[..4], [
+            "    var_a = sub(0, 1);",
+            "    var_b = modulo(divide(var_a, 7), 3);",
+            "    var_c = -(var_a < var_b);",
+            "    print(var_c);",
+        ]);
+    }
+}
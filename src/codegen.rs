@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use crate::ast::BinaryOp;
+use crate::backend::{self, Backend};
+use crate::ir::{BlockId, Cfg, Value};
+use crate::regalloc::Allocation;
+
+// Instruction addresses go up in steps of 5
+pub const STEP: i32 = 5;
+
+// Every instruction has an operation and three operands, e.g. `add r0 r1 r0`
+// or `jeq r1 r0 @.L2`. Jump targets name a label as `@label` until the code
+// is assembled. `out r1 0 0` writes a number and `in 0 0 r1` reads one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub op: String,
+    pub operands: [String; 3],
+    // The line and column of the statement it was made for, if any
+    pub source: Option<(usize, usize)>,
+}
+
+impl Instruction {
+    pub fn parse(text: &str) -> Instruction {
+        let mut parts = text.split_whitespace().map(String::from);
+        let mut operand = || parts.next().unwrap_or_else(|| String::from("0"));
+        let op = operand();
+        Instruction { op, operands: [operand(), operand(), operand()], source: None }
+    }
+
+    // The label this instruction jumps to, if it is a jump
+    pub fn target(&self) -> Option<&str> {
+        self.operands[2].strip_prefix('@')
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {} {}", self.op, self.operands[0], self.operands[1], self.operands[2])
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Line {
+    Instruction(Instruction),
+    Label(String),
+    Comment(String),
+}
+
+// Where a variable lives while the program runs
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    Register(String),
+    Ram(i32),
+}
+
+// Variables in the order they are declared, each in the register it was
+// allocated or else at the next free address in ram
+pub fn locations(cfg: &Cfg, allocation: &Allocation) -> Vec<(String, Location)> {
+    let mut sym_addr = 0;
+    cfg.variables.iter().map(|variable| {
+        let location = match allocation.get(&variable.name) {
+            Some(register) => Location::Register(register.clone()),
+            None => {
+                sym_addr += 1;
+                Location::Ram(sym_addr - 1)
+            }
+        };
+        (variable.name.clone(), location)
+    }).collect()
+}
+
+// Lowers the blocks of a program to instructions for the target processor.
+// Variables in ram are reached through bp. Expressions are worked out in
+// place when their operands are in registers, and otherwise in r0 with r1
+// and r2 as scratch. Comments are carried along to the assembly.
+pub fn generate(cfg: &Cfg, allocation: &Allocation) -> Vec<Line> {
+    let mut codegen = Codegen::new(locations(cfg, allocation).into_iter().collect());
+    backend::emit(cfg, &mut codegen);
+    codegen.place_label(String::from(".end"));
+    codegen.lines
+}
+
+// Gives every instruction its address and writes the assembly out, with
+// comments as `;` lines that take up no address
+pub fn assemble(lines: &[Line]) -> String {
+    let mut addresses = HashMap::new();
+    let mut address = 0;
+    for line in lines {
+        match line {
+            Line::Instruction(_) => address += STEP,
+            Line::Label(label) => { addresses.insert(label.as_str(), address); }
+            Line::Comment(_) => {}
+        }
+    }
+    let mut assembly = String::new();
+    for line in lines {
+        match line {
+            Line::Instruction(instruction) => match instruction.target() {
+                Some(label) => {
+                    let [a, b, _] = &instruction.operands;
+                    assembly.push_str(&format!("{} {} {} {}\n", instruction.op, a, b, addresses[label]));
+                }
+                None => assembly.push_str(&format!("{}\n", instruction)),
+            },
+            Line::Comment(text) => assembly.push_str(&format!("{}\n", format!("; {}", text).trim_end())),
+            Line::Label(_) => {}
+        }
+    }
+    assembly
+}
+
+// The address of every instruction made for a statement, with the line and
+// column of the statement, so debuggers and profilers can show the program
+// as it was written
+pub fn source_map(lines: &[Line]) -> Vec<(i32, usize, usize)> {
+    let mut map = Vec::new();
+    let mut address = 0;
+    for line in lines {
+        if let Line::Instruction(instruction) = line {
+            if let Some((line, column)) = instruction.source {
+                map.push((address, line, column));
+            }
+            address += STEP;
+        }
+    }
+    map
+}
+
+// A source map as JSON, naming the file it maps to:
+//
+//     {"file": "program.bas", "mappings": [{"address": 0, "line": 1, "column": 1}, ...]}
+pub fn source_map_json(file: &str, map: &[(i32, usize, usize)]) -> String {
+    let mut escaped = String::new();
+    for c in file.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    let mappings: Vec<String> = map.iter()
+        .map(|(address, line, column)| format!("    {{\"address\": {}, \"line\": {}, \"column\": {}}}", address, line, column))
+        .collect();
+    format!("{{\n  \"file\": \"{}\",\n  \"mappings\": [\n{}\n  ]\n}}\n", escaped, mappings.join(",\n"))
+}
+
+struct Codegen {
+    lines: Vec<Line>,
+    // The statement being generated, which instructions are marked with
+    position: Option<(usize, usize)>,
+    symbols: HashMap<String, Location>,
+    label_count: usize,
+    // The temporary waiting in r0, and the one parked in r2 while r0 is
+    // busy with something else
+    r0: Option<usize>,
+    r2: Option<usize>,
+}
+
+// Blocks are labelled by number, and labels made up inside a block count up
+// separately. Both start with a dot so they can never clash.
+fn block_label(block: BlockId) -> String {
+    format!(".B{}", block)
+}
+
+impl Codegen {
+    fn new(symbols: HashMap<String, Location>) -> Codegen {
+        Codegen {
+            lines: Vec::new(),
+            position: None,
+            symbols,
+            label_count: 0,
+            r0: None,
+            r2: None,
+        }
+    }
+
+    fn code_gen(&mut self, code: String) {
+        let mut instruction = Instruction::parse(&code);
+        instruction.source = self.position;
+        self.lines.push(Line::Instruction(instruction));
+    }
+
+    fn comment_gen(&mut self, text: &str) {
+        self.lines.push(Line::Comment(text.to_string()));
+    }
+
+    fn new_label(&mut self) -> String {
+        self.label_count += 1;
+        format!(".L{}", self.label_count)
+    }
+
+    fn place_label(&mut self, label: String) {
+        self.lines.push(Line::Label(label));
+    }
+
+    fn variable_register(&self, value: &Value) -> Option<String> {
+        match value {
+            Value::Var(name) => match &self.symbols[name] {
+                Location::Register(register) => Some(register.clone()),
+                Location::Ram(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Puts what an instruction just left in `register` where it belongs
+    fn result(&mut self, dest: &Value, register: &str) {
+        match dest {
+            Value::Temp(temp) => self.r0 = Some(*temp),
+            Value::Var(name) => match self.symbols[name].clone() {
+                Location::Register(variable) => self.code_gen(format!("addi {} 0 {}", register, variable)),
+                Location::Ram(address) => {
+                    self.code_gen(format!("set bp {} bp", address));
+                    self.code_gen(format!("addi {} 0 ram", register));
+                }
+            },
+            Value::Const(_) => panic!("Cannot assign to a constant"),
+        }
+    }
+
+    // The register a value can be read from where it is, if any. A
+    // temporary is used up by reading it.
+    fn register(&mut self, value: &Value) -> Option<String> {
+        match value {
+            Value::Var(_) => self.variable_register(value),
+            Value::Temp(temp) if self.r0 == Some(*temp) => {
+                self.r0 = None;
+                Some(String::from("r0"))
+            }
+            Value::Temp(temp) if self.r2 == Some(*temp) => {
+                self.r2 = None;
+                Some(String::from("r2"))
+            }
+            Value::Temp(temp) => panic!("Temporary %{} is not in a register", temp),
+            Value::Const(_) => None,
+        }
+    }
+
+    fn load(&mut self, value: &Value, register: &str) {
+        match (value, self.register(value)) {
+            (_, Some(from)) => {
+                if from != register {
+                    self.code_gen(format!("addi {} 0 {}", from, register));
+                }
+            }
+            (Value::Const(value), None) => self.code_gen(format!("set {} {} {}", register, value, register)),
+            (Value::Var(name), None) => {
+                let Location::Ram(address) = self.symbols[name] else { unreachable!() };
+                self.code_gen(format!("set bp {} bp", address));
+                self.code_gen(format!("addi ram 0 {}", register));
+            }
+            (Value::Temp(_), None) => unreachable!(),
+        }
+    }
+
+    // Makes way in r0 by parking the temporary waiting there in r2, unless
+    // it is `keep`
+    fn clear_r0(&mut self, keep: &Value) {
+        if let Some(temp) = self.r0 {
+            if *keep != Value::Temp(temp) {
+                if self.r2.is_some() {
+                    panic!("Out of scratch registers");
+                }
+                self.code_gen("addi r0 0 r2".to_string());
+                self.r0 = None;
+                self.r2 = Some(temp);
+            }
+        }
+    }
+
+    fn load_r0(&mut self, value: &Value) {
+        self.clear_r0(value);
+        self.load(value, "r0");
+    }
+
+    // A register holding the value, loading it into `scratch` if need be
+    fn operand(&mut self, value: &Value, scratch: &str) -> String {
+        match self.register(value) {
+            Some(register) => register,
+            None => {
+                if scratch == "r0" {
+                    self.load_r0(value);
+                } else {
+                    self.load(value, scratch);
+                }
+                scratch.to_string()
+            }
+        }
+    }
+
+    fn is_in_r0(&self, value: &Value) -> bool {
+        matches!(value, Value::Temp(temp) if self.r0 == Some(*temp))
+    }
+
+    // Works out `left op right` straight into the variable's register when
+    // it has one, and into r0 otherwise
+    fn calculate(&mut self, op: BinaryOp, left: &Value, right: &Value, dest: &Value) {
+        // Adding a constant takes a single `addi`
+        let immediate = match (op, right) {
+            (BinaryOp::Add, Value::Const(value)) => Some(*value),
+            (BinaryOp::Sub, Value::Const(value)) => Some(value.wrapping_neg()),
+            _ => None,
+        };
+        // The right side may have just been worked out in r0, which the left
+        // side is about to need
+        let early = if immediate.is_none() && self.is_in_r0(right) && self.variable_register(left).is_none() {
+            self.load(right, "r1");
+            Some(String::from("r1"))
+        } else {
+            None
+        };
+        let a = self.operand(left, "r0");
+        let b = match (immediate, early) {
+            (Some(value), _) => value.to_string(),
+            (None, Some(register)) => register,
+            (None, None) => self.operand(right, "r1"),
+        };
+        let target = self.variable_register(dest).unwrap_or_else(|| {
+            self.clear_r0(dest);
+            String::from("r0")
+        });
+        let instruction = match op {
+            _ if immediate.is_some() => "addi",
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Mod => "mod",
+            BinaryOp::Xor => "xor",
+            BinaryOp::And => "and",
+            BinaryOp::Or  => "or",
+            BinaryOp::Shl => "shl",
+            _ => panic!("Unknown operator {}", op.symbol()),
+        };
+        self.code_gen(format!("{} {} {} {}", instruction, a, b, target));
+        if target == "r0" {
+            self.result(dest, "r0");
+        }
+    }
+
+    // Jumps to `label` when the comparison holds. The right side is worked
+    // out after the left, so if either is a temporary still in r0 it is the
+    // right one.
+    fn compare(&mut self, op: BinaryOp, left: &Value, right: &Value, label: &str) {
+        let a = self.operand(left, "r1");
+        let b = self.operand(right, if a == "r0" { "r1" } else { "r0" });
+        let jump = match op {
+            BinaryOp::Equal        => "jeq",
+            BinaryOp::NotEqual     => "jne",
+            BinaryOp::Less         => "jlt",
+            BinaryOp::Greater      => "jgt",
+            BinaryOp::LessEqual    => "jle",
+            BinaryOp::GreaterEqual => "jge",
+            _ => panic!("Unknown condition {}", op.symbol()),
+        };
+        self.code_gen(format!("{} {} {} @{}", jump, a, b, label));
+    }
+}
+
+impl Backend for Codegen {
+    fn label(&mut self, block: BlockId) {
+        self.place_label(block_label(block));
+        self.r0 = None;
+        self.r2 = None;
+    }
+
+    fn copy(&mut self, dest: &Value, src: &Value) {
+        match (dest, self.variable_register(dest)) {
+            (_, Some(register)) => self.load(src, &register),
+            (Value::Var(_), None) => {
+                let register = self.operand(src, "r0");
+                self.result(dest, &register);
+            }
+            _ => {
+                self.load_r0(src);
+                self.result(dest, "r0");
+            }
+        }
+    }
+
+    fn arithmetic(&mut self, op: BinaryOp, dest: &Value, left: &Value, right: &Value) {
+        if !op.is_relation() {
+            self.calculate(op, left, right, dest);
+            return;
+        }
+        let holds = self.new_label();
+        let done = self.new_label();
+        self.compare(op, left, right, &holds);
+        // A comparison leaves -1 (true) or 0 (false), like traditional BASIC
+        self.code_gen("set r0 0 r0".to_string());
+        self.code_gen(format!("jmp 0 0 @{}", done));
+        self.place_label(holds);
+        self.code_gen("set r0 -1 r0".to_string());
+        self.place_label(done);
+        self.result(dest, "r0");
+    }
+
+    fn print(&mut self, value: &Value) {
+        let register = self.operand(value, "r1");
+        self.code_gen(format!("out {} 0 0", register));
+    }
+
+    fn input(&mut self, dest: &Value) {
+        match self.variable_register(dest) {
+            Some(register) => self.code_gen(format!("in 0 0 {}", register)),
+            None => {
+                self.code_gen("in 0 0 r1".to_string());
+                self.result(dest, "r1");
+            }
+        }
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.comment_gen(text);
+    }
+
+    fn position(&mut self, line: usize, column: usize) {
+        self.position = Some((line, column));
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.code_gen(format!("jmp 0 0 @{}", block_label(target)));
+    }
+
+    fn branch(&mut self, op: BinaryOp, left: &Value, right: &Value, target: BlockId) {
+        self.compare(op, left, right, &block_label(target));
+    }
+
+    fn call(&mut self, target: BlockId, _after: BlockId) {
+        self.code_gen(format!("call 0 0 @{}", block_label(target)));
+    }
+
+    fn ret(&mut self) {
+        self.code_gen("ret 0 0 0".to_string());
+    }
+
+    fn halt(&mut self) {
+        self.code_gen("jmp 0 0 @.end".to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lower, Options};
+
+    fn code(source: &str) -> Vec<String> {
+        let cfg = lower(source, &Options::default()).unwrap();
+        assemble(&generate(&cfg, &Allocation::new())).lines().map(String::from).collect()
+    }
+
+    // The instruction that the jump at `index` goes to, counting 5
+    // addresses to an instruction
+    fn target(code: &[String], index: usize) -> &str {
+        let address: usize = code[index].rsplit(' ').next().unwrap().parse().unwrap();
+        &code[address / 5]
+    }
+
+    #[test]
+    fn comparisons_leave_minus_one_or_zero() {
+        let code = code("let a = 3\nlet b = a < 5\n");
+        let jump = code.iter().position(|line| line.starts_with("jlt")).unwrap();
+        assert_eq!(target(&code, jump), "set r0 -1 r0");
+        assert_eq!(code[jump + 1], "set r0 0 r0");
+        // Both ways on, the value is stored in b
+        assert_eq!(target(&code, jump + 2), "set bp 1 bp");
+        assert_eq!(code[jump + 4], "set bp 1 bp");
+        assert_eq!(code[jump + 5], "addi r0 0 ram");
+    }
+
+    #[test]
+    fn comparisons_can_be_combined_and_tested() {
+        let code = code("let a = 3\nlet b = a < 5\nlet c = b & a > 1\nif c then\nlet d = 1\nend if\n");
+        assert_eq!(code.iter().filter(|line| line.as_str() == "set r0 -1 r0").count(), 2);
+        assert!(code.contains(&String::from("and r0 r1 r0")));
+        assert!(code.contains(&String::from("set bp 3 bp")));
+    }
+
+    #[test]
+    fn comments_are_kept_in_the_assembly_without_an_address() {
+        assert_eq!(code("let a = 1\n' note\nrem\nlet b = 2\n"), ["set r0 1 r0", "set bp 0 bp", "addi r0 0 ram", "; note", ";", "set r0 2 r0", "set bp 1 bp", "addi r0 0 ram"]);
+        assert_eq!(code("let a = 1\n' note\nlabel here\ngoto here\n").last().unwrap(), "jmp 0 0 15");
+    }
+
+    #[test]
+    fn statements_end_at_a_newline_or_colon() {
+        let expected = code("let x = 1\nlet y = 2\n");
+        assert_eq!(code("let x = 1 : let y = 2"), expected);
+        assert_eq!(code("let x = 1 ' one\n\nlet y = 2 : ' two"), [&expected[..3], &["; one".to_string()], &expected[3..], &["; two".to_string()]].concat());
+    }
+
+    #[test]
+    fn one_line_ifs_skip_the_rest_of_the_line() {
+        let code = code("10 LET X = 1\n20 IF X THEN X = 2 : Y = 3\n30 Z = 4\n");
+        // The IF jumps past both statements after THEN, but not line 30
+        let skip = code.iter().position(|line| line.starts_with('j')).unwrap();
+        assert_eq!(target(&code, skip), "set r0 4 r0");
+    }
+
+    #[test]
+    fn classic_listings_jump_to_line_numbers() {
+        let code = code("10 LET X = 1\n20 IF X = 1 THEN 100\n30 IF X THEN GOTO 100\n40 X = X + 1\n100 END\n");
+        // Line 100 is the last instruction, and both ways of going there do
+        let end = format!("jmp 0 0 {}", 5 * (code.len() - 1));
+        assert_eq!(code.iter().filter(|line| **line == end).count(), 2);
+        assert_eq!(code.iter().filter(|line| line.starts_with("jeq")).count(), 1);
+    }
+
+    // The line of each address in the source map of `source`
+    fn lines(source: &str, opt_level: u8) -> Vec<(i32, usize)> {
+        let output = crate::compile(source, &Options { opt_level, ..Options::default() }).unwrap();
+        let instructions = output.assembly.lines().filter(|line| !line.starts_with(';')).count();
+        assert_eq!(output.source_map.len(), instructions);
+        output.source_map.iter().map(|(address, line, _)| (*address, *line)).collect()
+    }
+
+    #[test]
+    fn every_address_maps_to_its_statement() {
+        let source = "let a = 1\nlet b = a + 2\nwhile b < 5 do\n  b = b + 1\nend while\n";
+        for opt_level in 0..=2 {
+            let lines = lines(source, opt_level);
+            assert!(lines.iter().enumerate().all(|(index, (address, _))| *address == STEP * index as i32));
+            // Going round again belongs to the while, not the body
+            let after: Vec<usize> = lines.iter().map(|(_, line)| *line).skip_while(|line| *line != 4).skip_while(|line| *line == 4).collect();
+            assert!(!after.is_empty() && after.iter().all(|line| *line == 3));
+        }
+        let output = crate::compile(source, &Options::default()).unwrap();
+        assert!(output.source_map.iter().any(|(_, line, column)| (*line, *column) == (4, 3)));
+    }
+
+    #[test]
+    fn source_maps_are_json_with_the_file_name_escaped() {
+        let json = source_map_json("a \"b\"\\c\n.bas", &[(0, 1, 1), (5, 2, 3)]);
+        assert_eq!(json, "{\n  \"file\": \"a \\\"b\\\"\\\\c\\u000a.bas\",\n  \"mappings\": [\n    {\"address\": 0, \"line\": 1, \"column\": 1},\n    {\"address\": 5, \"line\": 2, \"column\": 3}\n  ]\n}\n");
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use crate::ast::BinaryOp;
+use crate::diagnostic::Diagnostic;
+use crate::ir::{Cfg, Inst, Terminator, Value};
+
+// Dead code elimination. A branch on two constants always goes the same way,
+// so it becomes a jump. Jumps to an empty block that only jumps on go
+// straight to where it leads, and blocks that can then never be reached are
+// dropped from the layout. Instructions that store to a variable or
+// temporary nothing ever reads are removed, unless they read input, call
+// the host or divide by something that may be zero, and so are variables
+// that end up with no instructions left to mention them. The host's
+// variables count as read at the end.
+pub fn eliminate(cfg: &mut Cfg) {
+    decide_branches(cfg);
+    thread_jumps(cfg);
+    let reachable = cfg.reachable();
+    cfg.order.retain(|&block| reachable[block]);
+    loop {
+        let mut read = reads(cfg);
+        read.extend(cfg.variables.iter().filter(|variable| variable.global).map(|variable| Value::Var(variable.name.clone())));
+        let mut changed = false;
+        for &block in &cfg.order {
+            let insts = &mut cfg.blocks[block].insts;
+            let before = insts.len();
+            insts.retain(|inst| has_effect(inst) || inst.dest().is_none_or(|dest| read.contains(dest)));
+            changed |= insts.len() != before;
+        }
+        if !changed {
+            break;
+        }
+    }
+    let mut mentioned = reads(cfg);
+    for &block in &cfg.order {
+        mentioned.extend(cfg.blocks[block].insts.iter().filter_map(Inst::dest).cloned());
+    }
+    cfg.variables.retain(|variable| variable.global || mentioned.contains(&Value::Var(variable.name.clone())));
+}
+
+// Whether an instruction does more than store its result, so it has to
+// stay even if nothing reads that
+fn has_effect(inst: &Inst) -> bool {
+    match inst {
+        Inst::Input(_) | Inst::Call { .. } => true,
+        Inst::Binary { op: BinaryOp::Div | BinaryOp::Mod, right, .. } => !matches!(right, Value::Const(value) if *value != 0),
+        _ => false,
+    }
+}
+
+// Warns about statements that can never run and variables that are declared
+// but never read. Code that follows on from other unreachable code is only
+// reported once, at the start.
+pub fn warnings(cfg: &Cfg) -> Vec<Diagnostic> {
+    let mut cfg = cfg.clone();
+    decide_branches(&mut cfg);
+    let reachable = cfg.reachable();
+    let mut warnings = Vec::new();
+    let mut reported = false;
+    for &block in &cfg.order {
+        if reachable[block] {
+            reported = false;
+        } else if let Some((line, column)) = cfg.blocks[block].source {
+            if !reported {
+                warnings.push(Diagnostic::new("Unreachable code".to_string(), line, column));
+                reported = true;
+            }
+        }
+    }
+    cfg.order.retain(|&block| reachable[block]);
+    let read = reads(&cfg);
+    for variable in &cfg.variables {
+        if !variable.global && !read.contains(&Value::Var(variable.name.clone())) {
+            warnings.push(Diagnostic::new(format!("Variable {} is never used", variable.name), variable.line, variable.column));
+        }
+    }
+    warnings.sort_by_key(|warning| (warning.line, warning.column));
+    warnings
+}
+
+// Everything the blocks in the layout read
+fn reads(cfg: &Cfg) -> HashSet<Value> {
+    let mut read = HashSet::new();
+    for &block in &cfg.order {
+        let block = &cfg.blocks[block];
+        for inst in &block.insts {
+            read.extend(inst.uses().into_iter().cloned());
+        }
+        read.extend(block.terminator.uses().into_iter().cloned());
+    }
+    read
+}
+
+fn decide_branches(cfg: &mut Cfg) {
+    for block in &mut cfg.blocks {
+        if let Terminator::Branch { op, left: Value::Const(a), right: Value::Const(b), then, otherwise } = block.terminator {
+            let holds = match op {
+                BinaryOp::Equal        => a == b,
+                BinaryOp::NotEqual     => a != b,
+                BinaryOp::Less         => a < b,
+                BinaryOp::Greater      => a > b,
+                BinaryOp::LessEqual    => a <= b,
+                BinaryOp::GreaterEqual => a >= b,
+                _ => continue,
+            };
+            block.terminator = Terminator::Jump(if holds { then } else { otherwise });
+        }
+    }
+}
+
+fn thread_jumps(cfg: &mut Cfg) {
+    for block in 0..cfg.blocks.len() {
+        for successor in cfg.blocks[block].terminator.successors() {
+            // Stops if the jumps go round in a circle
+            let mut target = successor;
+            let mut seen = HashSet::from([target]);
+            while let (true, Terminator::Jump(next)) = (cfg.blocks[target].is_empty(), &cfg.blocks[target].terminator) {
+                if !seen.insert(*next) {
+                    break;
+                }
+                target = *next;
+            }
+            cfg.blocks[block].terminator.retarget(successor, target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile, lower, Options};
+
+    fn optimized(source: &str) -> Cfg {
+        lower(source, &Options { opt_level: 1, ..Options::default() }).unwrap()
+    }
+
+    fn binaries(cfg: &Cfg) -> Vec<(BinaryOp, Value)> {
+        cfg.order.iter()
+            .flat_map(|&block| &cfg.blocks[block].insts)
+            .filter_map(|inst| match inst {
+                Inst::Binary { op, right, .. } => Some((*op, right.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unused_stores_and_unreachable_blocks_go() {
+        let cfg = optimized("let a = 4\nlet b = a + 1\nif 1 == 2 then\nlet c = a * 3\nend if\nwhile a < 9 do\na = a + 2\nend while\n");
+        assert_eq!(binaries(&cfg), [(BinaryOp::Add, Value::Const(2))]);
+        assert_eq!(cfg.variables.iter().map(|variable| variable.name.as_str()).collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[test]
+    fn unused_divisions_that_may_fail_stay() {
+        let cfg = optimized("let a = 0\nlet b = 5 / a\nlet c = a % 3\nlet d = a / 0\n");
+        assert_eq!(binaries(&cfg), [(BinaryOp::Div, Value::Var("a".to_string())), (BinaryOp::Div, Value::Const(0))]);
+        assert!(compile("let a = 0\nlet b = 5 / a\n", &Options { opt_level: 1, ..Options::default() }).is_ok());
+    }
+
+    #[test]
+    fn warnings_are_about_the_program_as_written() {
+        let source = "let x = 3\nlet y = x * 0\nwhile y < 1 do\ny = y + 1\nend while\ngoto done\nlet z = y\nlabel done\n";
+        for opt_level in [0, 1, 2] {
+            let warnings = compile(source, &Options { opt_level, ..Options::default() }).unwrap().warnings;
+            assert_eq!(warnings, [
+                Diagnostic::new("Unreachable code".to_string(), 7, 1),
+                Diagnostic::new("Variable z is never used".to_string(), 7, 1),
+            ], "at -O{}", opt_level);
+        }
+    }
+}
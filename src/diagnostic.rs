@@ -0,0 +1,30 @@
+use crate::lexer::LexError;
+
+// An error found while compiling, at a line and column (both from 1) of the
+// source as written
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, line: usize, column: usize) -> Diagnostic {
+        Diagnostic { message, line, column }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+impl From<LexError> for Diagnostic {
+    fn from(error: LexError) -> Diagnostic {
+        Diagnostic::new(error.message, error.line, error.column)
+    }
+}
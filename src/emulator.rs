@@ -0,0 +1,945 @@
+use std::collections::BTreeMap;
+use crate::codegen::{Instruction, Location, STEP};
+
+// Runs assembled code for the target processor, so what a compiled program
+// does and how many instructions it takes can be checked without the
+// hardware. Registers and ram hold signed words that wrap around like the
+// target's, and ram starts out all zero. `out` writes a number and `in`
+// reads one, giving 0 once the input runs out.
+
+// A program gets this many instructions before it is assumed to loop forever
+pub const STEP_LIMIT: u64 = 10_000_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // Address of the instruction that failed
+    pub address: usize,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at address {}", self.message, self.address)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// The state of the processor once a program has run to the end
+#[derive(Clone, Debug, PartialEq)]
+pub struct Machine {
+    pub registers: Vec<i64>,
+    pub bp: i64,
+    pub ram: BTreeMap<i64, i64>,
+    // How many instructions were executed
+    pub steps: u64,
+    // Every number the program wrote, in order
+    pub output: Vec<i64>,
+}
+
+impl Machine {
+    // A machine with all registers and ram zero, before it has run
+    pub fn new(registers: usize) -> Machine {
+        Machine { registers: vec![0; registers], bp: 0, ram: BTreeMap::new(), steps: 0, output: Vec::new() }
+    }
+
+    // Where a variable's value ended up, given where codegen put it
+    pub fn read(&self, location: &Location) -> i64 {
+        match location {
+            Location::Register(register) => {
+                let index: usize = register[1..].parse().unwrap();
+                self.registers.get(index).copied().unwrap_or(0)
+            }
+            Location::Ram(address) => self.ram.get(&(*address as i64)).copied().unwrap_or(0),
+        }
+    }
+}
+
+pub fn run(assembly: &str, registers: usize, word_bits: u32, input: &mut dyn Iterator<Item = i64>) -> Result<Machine, RuntimeError> {
+    let mut machine = Machine::new(registers);
+    run_on(&mut machine, assembly, word_bits, input)?;
+    Ok(machine)
+}
+
+// Runs a program on a machine the caller keeps, so what it printed before
+// failing can still be read
+pub fn run_on(machine: &mut Machine, assembly: &str, word_bits: u32, input: &mut dyn Iterator<Item = i64>) -> Result<(), RuntimeError> {
+    let program: Vec<Instruction> = assembly.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .map(Instruction::parse)
+        .collect();
+    let mut emulator = Emulator {
+        machine,
+        word_bits,
+        address: 0,
+    };
+    let mut stack = Vec::new();
+    while let Some(instruction) = program.get(emulator.address / STEP as usize) {
+        if emulator.machine.steps == STEP_LIMIT {
+            return Err(emulator.error(format!("Stopped after {} instructions", STEP_LIMIT)));
+        }
+        emulator.machine.steps += 1;
+        let [a, b, c] = &instruction.operands;
+        let mut next = emulator.address + STEP as usize;
+        match instruction.op.as_str() {
+            "set" => {
+                let value = emulator.immediate(b)?;
+                emulator.write(c, value)?;
+            }
+            "addi" => {
+                let value = emulator.read(a)?.wrapping_add(emulator.immediate(b)?);
+                emulator.write(c, value)?;
+            }
+            "add" | "sub" | "mul" | "div" | "mod" | "xor" | "and" | "or" | "shl" => {
+                let (x, y) = (emulator.read(a)?, emulator.read(b)?);
+                let value = match instruction.op.as_str() {
+                    "add" => x.wrapping_add(y),
+                    "sub" => x.wrapping_sub(y),
+                    "mul" => x.wrapping_mul(y),
+                    "div" => x.checked_div(y).ok_or_else(|| emulator.error("Division by zero".to_string()))?,
+                    "mod" => x.checked_rem(y).ok_or_else(|| emulator.error("Division by zero".to_string()))?,
+                    "xor" => x ^ y,
+                    "and" => x & y,
+                    "or"  => x | y,
+                    _     => x.wrapping_shl(y as u32),
+                };
+                emulator.write(c, value)?;
+            }
+            "out" => {
+                let value = emulator.read(a)?;
+                emulator.machine.output.push(value);
+            }
+            "in" => emulator.write(c, input.next().unwrap_or(0))?,
+            "jmp" => next = emulator.target(c)?,
+            "call" => {
+                stack.push(next);
+                next = emulator.target(c)?;
+            }
+            "ret" => next = stack.pop().ok_or_else(|| emulator.error("Return without a call".to_string()))?,
+            "jeq" | "jne" | "jlt" | "jgt" | "jle" | "jge" => {
+                let (x, y) = (emulator.read(a)?, emulator.read(b)?);
+                let holds = match instruction.op.as_str() {
+                    "jeq" => x == y,
+                    "jne" => x != y,
+                    "jlt" => x < y,
+                    "jgt" => x > y,
+                    "jle" => x <= y,
+                    _     => x >= y,
+                };
+                if holds {
+                    next = emulator.target(c)?;
+                }
+            }
+            op => return Err(emulator.error(format!("Unknown instruction {}", op))),
+        }
+        emulator.address = next;
+    }
+    Ok(())
+}
+
+struct Emulator<'a> {
+    machine: &'a mut Machine,
+    word_bits: u32,
+    address: usize,
+}
+
+impl Emulator<'_> {
+    fn error(&self, message: String) -> RuntimeError {
+        RuntimeError { message, address: self.address }
+    }
+
+    fn immediate(&self, operand: &str) -> Result<i64, RuntimeError> {
+        operand.parse().map_err(|_| self.error(format!("Expected a number, found {}", operand)))
+    }
+
+    fn target(&self, operand: &str) -> Result<usize, RuntimeError> {
+        operand.parse().map_err(|_| self.error(format!("Expected an address, found {}", operand)))
+    }
+
+    fn register(&self, operand: &str) -> Result<usize, RuntimeError> {
+        operand.strip_prefix('r')
+            .and_then(|index| index.parse().ok())
+            .filter(|&index| index < self.machine.registers.len())
+            .ok_or_else(|| self.error(format!("Unknown register {}", operand)))
+    }
+
+    fn read(&self, operand: &str) -> Result<i64, RuntimeError> {
+        match operand {
+            "bp" => Ok(self.machine.bp),
+            "ram" => Ok(self.machine.ram.get(&self.machine.bp).copied().unwrap_or(0)),
+            _ => Ok(self.machine.registers[self.register(operand)?]),
+        }
+    }
+
+    fn write(&mut self, operand: &str, value: i64) -> Result<(), RuntimeError> {
+        // Sign-extends the low word_bits bits
+        let shift = 64 - self.word_bits;
+        let value = (value << shift) >> shift;
+        match operand {
+            "bp" => self.machine.bp = value,
+            "ram" => { self.machine.ram.insert(self.machine.bp, value); }
+            _ => {
+                let index = self.register(operand)?;
+                self.machine.registers[index] = value;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs RV32I assembly as the riscv module writes it, for Linux: enough of GNU
+// as syntax and its pseudo-instructions to cover that, and the write, read
+// and exit system calls. Code starts at TEXT with every instruction taking
+// 4 bytes, pseudo-instructions included, data follows at DATA, and the
+// stack grows down from STACK.
+const TEXT: u32 = 0x1_0000;
+const DATA: u32 = 0x10_0000;
+const STACK: u32 = 0x7fff_fff0;
+
+// What an RV32I program did by the time it exited
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rv32 {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: i32,
+    pub steps: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Register(usize),
+    Immediate(i64),
+    // offset(register)
+    Memory(i64, usize),
+    // The address of a label
+    Symbol(u32),
+}
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+fn register_number(name: &str) -> Option<usize> {
+    match name {
+        "fp" => Some(8),
+        _ => REGISTER_NAMES.iter().position(|&register| register == name)
+            .or_else(|| name.strip_prefix('x').and_then(|index| index.parse().ok()).filter(|&index| index < 32)),
+    }
+}
+
+// Drops a comment starting with `marker`, unless it is inside a string
+fn strip_comment(line: &str, marker: char) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == marker && !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => bytes.push(b'\n'),
+                Some('t') => bytes.push(b'\t'),
+                Some('0') => bytes.push(0),
+                Some(other) => bytes.extend(other.to_string().bytes()),
+                None => {}
+            },
+            _ => bytes.extend(c.to_string().bytes()),
+        }
+    }
+    bytes
+}
+
+pub fn run_rv32(assembly: &str, input: &mut dyn std::io::Read) -> Result<Rv32, RuntimeError> {
+    // Lays out code and data and finds every label first, since code can
+    // refer to labels further on
+    let mut labels = std::collections::HashMap::new();
+    let mut code: Vec<(String, Vec<String>)> = Vec::new();
+    let mut data: Vec<u8> = Vec::new();
+    let mut in_text = true;
+    for line in assembly.lines() {
+        let mut line = strip_comment(line, '#').trim();
+        while let Some((label, rest)) = line.split_once(':').filter(|(label, _)| !label.contains(char::is_whitespace) && !label.contains('"')) {
+            let address = if in_text { TEXT + 4 * code.len() as u32 } else { DATA + data.len() as u32 };
+            labels.insert(label.to_string(), address);
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match name {
+            ".text" => in_text = true,
+            ".data" | ".bss" | ".rodata" => in_text = false,
+            ".section" => in_text = rest.starts_with(".text"),
+            ".globl" | ".global" => {}
+            ".p2align" | ".align" => {
+                let alignment = 1 << rest.parse::<u32>().unwrap_or(0);
+                while !data.len().is_multiple_of(alignment) {
+                    data.push(0);
+                }
+            }
+            ".word" => for value in rest.split(',') {
+                data.extend((value.trim().parse::<i64>().unwrap_or(0) as u32).to_le_bytes());
+            },
+            ".zero" => data.extend(vec![0; rest.parse().unwrap_or(0)]),
+            ".ascii" => data.extend(unescape(rest.trim_matches('"'))),
+            _ => {
+                let operands = if rest.is_empty() { Vec::new() } else { rest.split(',').map(|operand| operand.trim().to_string()).collect() };
+                code.push((name.to_string(), operands));
+            }
+        }
+    }
+    let mut cpu = Cpu { registers: [0; 32], memory: std::collections::HashMap::new(), pc: TEXT, steps: 0 };
+    for (offset, byte) in data.into_iter().enumerate() {
+        cpu.memory.insert(DATA + offset as u32, byte);
+    }
+    cpu.registers[2] = STACK;
+    let mut result = Rv32 { stdout: Vec::new(), stderr: Vec::new(), exit_code: 0, steps: 0 };
+    loop {
+        let index = cpu.pc.wrapping_sub(TEXT) / 4;
+        let Some((op, operands)) = code.get(index as usize) else {
+            return Err(cpu.error(format!("Jumped outside the program to {:#x}", cpu.pc)));
+        };
+        if cpu.steps == STEP_LIMIT {
+            return Err(cpu.error(format!("Stopped after {} instructions", STEP_LIMIT)));
+        }
+        cpu.steps += 1;
+        let operands = operands.iter()
+            .map(|operand| cpu.operand(operand, &labels))
+            .collect::<Result<Vec<Operand>, RuntimeError>>()?;
+        let mut next = cpu.pc.wrapping_add(4);
+        let [a, b, c] = [0, 1, 2].map(|index| operands.get(index).cloned().unwrap_or(Operand::Immediate(0)));
+        match (op.as_str(), &a, &b, &c) {
+            ("li", Operand::Register(d), Operand::Immediate(value), _) => cpu.set(*d, *value as u32),
+            ("la", Operand::Register(d), Operand::Symbol(address), _) => cpu.set(*d, *address),
+            ("mv", Operand::Register(d), Operand::Register(s), _) => cpu.set(*d, cpu.registers[*s]),
+            ("neg", Operand::Register(d), Operand::Register(s), _) => cpu.set(*d, cpu.registers[*s].wrapping_neg()),
+            ("not", Operand::Register(d), Operand::Register(s), _) => cpu.set(*d, !cpu.registers[*s]),
+            ("seqz", Operand::Register(d), Operand::Register(s), _) => cpu.set(*d, (cpu.registers[*s] == 0) as u32),
+            ("snez", Operand::Register(d), Operand::Register(s), _) => cpu.set(*d, (cpu.registers[*s] != 0) as u32),
+            ("lw", Operand::Register(d), address, _) => {
+                let address = cpu.address(address)?;
+                let word = u32::from_le_bytes([0, 1, 2, 3].map(|offset| cpu.load(address + offset)));
+                cpu.set(*d, word);
+            }
+            ("lbu", Operand::Register(d), address, _) => {
+                let address = cpu.address(address)?;
+                cpu.set(*d, cpu.load(address) as u32);
+            }
+            ("sw", Operand::Register(s), address, _) => {
+                let address = cpu.address(address)?;
+                for (offset, byte) in cpu.registers[*s].to_le_bytes().into_iter().enumerate() {
+                    cpu.memory.insert(address + offset as u32, byte);
+                }
+            }
+            ("sb", Operand::Register(s), address, _) => {
+                let address = cpu.address(address)?;
+                cpu.memory.insert(address, cpu.registers[*s] as u8);
+            }
+            ("add" | "sub" | "and" | "or" | "xor" | "sll" | "srl" | "sra" | "slt" | "sltu", Operand::Register(d), Operand::Register(x), Operand::Register(y)) => {
+                let value = alu(op, cpu.registers[*x], cpu.registers[*y]);
+                cpu.set(*d, value);
+            }
+            ("addi" | "andi" | "ori" | "xori" | "slli" | "srli" | "srai" | "slti" | "sltiu", Operand::Register(d), Operand::Register(x), Operand::Immediate(value)) => {
+                let value = alu(op.trim_end_matches('i'), cpu.registers[*x], *value as u32);
+                cpu.set(*d, value);
+            }
+            ("beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu", Operand::Register(x), Operand::Register(y), Operand::Symbol(target)) => {
+                if compare(op, cpu.registers[*x], cpu.registers[*y]) {
+                    next = *target;
+                }
+            }
+            ("beqz" | "bnez" | "bltz" | "bgez", Operand::Register(x), Operand::Symbol(target), _) => {
+                if compare(&op[..op.len() - 1], cpu.registers[*x], 0) {
+                    next = *target;
+                }
+            }
+            ("j", Operand::Symbol(target), _, _) => next = *target,
+            ("call" | "jal", Operand::Symbol(target), _, _) => {
+                cpu.set(1, next);
+                next = *target;
+            }
+            ("ret", _, _, _) => next = cpu.registers[1],
+            ("jr", Operand::Register(s), _, _) => next = cpu.registers[*s],
+            ("ecall", _, _, _) => {
+                let [a0, a1, a2] = [10, 11, 12].map(|register| cpu.registers[register]);
+                match cpu.registers[17] {
+                    // write
+                    64 => {
+                        let bytes: Vec<u8> = (0..a2).map(|offset| cpu.load(a1 + offset)).collect();
+                        match a0 {
+                            1 => result.stdout.extend(bytes),
+                            2 => result.stderr.extend(bytes),
+                            _ => return Err(cpu.error(format!("Cannot write to file {}", a0))),
+                        }
+                        cpu.set(10, a2);
+                    }
+                    // read
+                    63 => {
+                        let mut buffer = vec![0; a2 as usize];
+                        let read = if a0 == 0 { input.read(&mut buffer).unwrap_or(0) } else { 0 };
+                        for (offset, &byte) in buffer[..read].iter().enumerate() {
+                            cpu.memory.insert(a1 + offset as u32, byte);
+                        }
+                        cpu.set(10, read as u32);
+                    }
+                    // exit
+                    93 => {
+                        result.exit_code = a0 as i32;
+                        result.steps = cpu.steps;
+                        return Ok(result);
+                    }
+                    call => return Err(cpu.error(format!("Unknown system call {}", call))),
+                }
+            }
+            _ => return Err(cpu.error(format!("Cannot run {} {}", op, code[index as usize].1.join(", ")))),
+        }
+        cpu.pc = next;
+    }
+}
+
+fn alu(op: &str, x: u32, y: u32) -> u32 {
+    match op {
+        "add" | "addi" => x.wrapping_add(y),
+        "sub" => x.wrapping_sub(y),
+        "and" => x & y,
+        "or"  => x | y,
+        "xor" => x ^ y,
+        "sll" => x << (y & 31),
+        "srl" => x >> (y & 31),
+        "sra" => ((x as i32) >> (y & 31)) as u32,
+        "slt" => ((x as i32) < (y as i32)) as u32,
+        _     => (x < y) as u32,
+    }
+}
+
+fn compare(op: &str, x: u32, y: u32) -> bool {
+    match op {
+        "beq"  => x == y,
+        "bne"  => x != y,
+        "blt"  => (x as i32) < (y as i32),
+        "bge"  => (x as i32) >= (y as i32),
+        "bltu" => x < y,
+        _      => x >= y,
+    }
+}
+
+struct Cpu {
+    registers: [u32; 32],
+    memory: std::collections::HashMap<u32, u8>,
+    pc: u32,
+    steps: u64,
+}
+
+impl Cpu {
+    fn error(&self, message: String) -> RuntimeError {
+        RuntimeError { message, address: self.pc as usize }
+    }
+
+    // x0 is always zero
+    fn set(&mut self, register: usize, value: u32) {
+        if register != 0 {
+            self.registers[register] = value;
+        }
+    }
+
+    fn load(&self, address: u32) -> u8 {
+        self.memory.get(&address).copied().unwrap_or(0)
+    }
+
+    fn address(&self, operand: &Operand) -> Result<u32, RuntimeError> {
+        match operand {
+            Operand::Memory(offset, base) => Ok(self.registers[*base].wrapping_add(*offset as u32)),
+            Operand::Symbol(address) => Ok(*address),
+            _ => Err(self.error("Expected an address".to_string())),
+        }
+    }
+
+    fn operand(&self, text: &str, labels: &std::collections::HashMap<String, u32>) -> Result<Operand, RuntimeError> {
+        if let Some(register) = register_number(text) {
+            return Ok(Operand::Register(register));
+        }
+        if let Ok(value) = text.parse::<i64>() {
+            return Ok(Operand::Immediate(value));
+        }
+        if let Some((offset, base)) = text.strip_suffix(')').and_then(|text| text.split_once('(')) {
+            let offset = if offset.is_empty() { Ok(0) } else { offset.parse::<i64>() };
+            if let (Ok(offset), Some(base)) = (offset, register_number(base)) {
+                return Ok(Operand::Memory(offset, base));
+            }
+        }
+        labels.get(text).map(|&address| Operand::Symbol(address))
+            .ok_or_else(|| self.error(format!("Unknown label or operand {}", text)))
+    }
+}
+
+// Runs 6502 assembly as the mos6502 module writes it for ca65, with jsr to
+// the C64 KERNAL's CHROUT and CHRIN writing and reading a character: the
+// newline is a return ($0D) on the C64, and CHRIN gives 0 at the end of the
+// input. They can be called with jsr or jumped to from a routine.
+// Instructions are run from their text rather than assembled, each taking 3
+// bytes of address space from CODE, and data follows them. The program
+// starts at the first instruction as if called with jsr, and stops when it
+// returns.
+const CODE: u32 = 0x0810;
+const EXIT: u32 = 0xff00;
+const CHROUT: u32 = 0xffd2;
+const CHRIN: u32 = 0xffcf;
+
+// What a 6502 program wrote and how long it took
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mos6502 {
+    pub output: Vec<u8>,
+    pub steps: u64,
+    // Whether it ended through the runtime's basic_fail, after writing why
+    pub failed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Implied,
+    Immediate(u8),
+    Absolute(u32),
+    AbsoluteX(u32),
+    AbsoluteY(u32),
+}
+
+// Works out a ca65 expression: numbers in decimal, $hex or %binary,
+// characters, symbols, `<` and `>` for the low and high byte, and sums
+fn evaluate(text: &str, symbols: &std::collections::HashMap<String, i64>, scope: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Some(rest) = text.strip_prefix('<') {
+        return evaluate(rest, symbols, scope).map(|value| value & 0xff);
+    }
+    if let Some(rest) = text.strip_prefix('>') {
+        return evaluate(rest, symbols, scope).map(|value| (value >> 8) & 0xff);
+    }
+    let mut total = 0;
+    let mut sign = 1;
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let term: String = match chars.peek() {
+            Some('\'') => chars.by_ref().take(3).collect(),
+            _ => std::iter::from_fn(|| chars.next_if(|&c| c.is_alphanumeric() || "$%@_".contains(c))).collect(),
+        };
+        let value = if let Some(hex) = term.strip_prefix('$') {
+            i64::from_str_radix(hex, 16).ok()?
+        } else if let Some(binary) = term.strip_prefix('%') {
+            i64::from_str_radix(binary, 2).ok()?
+        } else if term.starts_with('\'') {
+            term.chars().nth(1)? as i64
+        } else if term.starts_with(|c: char| c.is_ascii_digit()) {
+            term.parse().ok()?
+        } else if term.starts_with('@') {
+            *symbols.get(&format!("{}{}", scope, term))?
+        } else {
+            *symbols.get(&term)?
+        };
+        total += sign * value;
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        sign = match chars.next() {
+            None => return Some(total),
+            Some('+') => 1,
+            Some('-') => -1,
+            Some(_) => return None,
+        };
+    }
+}
+
+pub fn run_6502(assembly: &str, input: &mut dyn std::io::Read) -> Result<Mos6502, RuntimeError> {
+    // Finds every symbol first, since code can refer to labels further on.
+    // Data is laid out once the size of the code is known, so data labels
+    // start as offsets.
+    let mut symbols = std::collections::HashMap::new();
+    let mut data_labels = Vec::new();
+    let mut code: Vec<(String, String, String)> = Vec::new();
+    let mut data: Vec<(String, String, String)> = Vec::new();
+    let mut data_size = 0;
+    let mut in_code = true;
+    let mut scope = String::new();
+    // Whether each .ifdef or .ifndef around here holds
+    let mut conditions: Vec<bool> = Vec::new();
+    for line in assembly.lines() {
+        let mut line = strip_comment(line, ';').trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match name {
+            ".ifdef" | ".ifndef" => {
+                conditions.push(symbols.contains_key(rest.trim()) == (name == ".ifdef"));
+                continue;
+            }
+            ".endif" => {
+                conditions.pop();
+                continue;
+            }
+            _ if conditions.contains(&false) => continue,
+            _ => {}
+        }
+        if let Some((name, value)) = line.split_once('=').filter(|(name, _)| !name.contains([':', '"', '.'])) {
+            let value = evaluate(value, &symbols, &scope)
+                .ok_or_else(|| RuntimeError { message: format!("Cannot work out {}", value.trim()), address: 0 })?;
+            symbols.insert(name.trim().to_string(), value);
+            continue;
+        }
+        while let Some((label, rest)) = line.split_once(':').filter(|(label, _)| !label.contains(char::is_whitespace) && !label.contains('"')) {
+            let label = if label.starts_with('@') {
+                format!("{}{}", scope, label)
+            } else {
+                scope = label.to_string();
+                label.to_string()
+            };
+            if in_code {
+                symbols.insert(label, (CODE + 3 * code.len() as u32) as i64);
+            } else {
+                data_labels.push((label, data_size));
+            }
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim().to_string();
+        match name {
+            ".segment" => in_code = rest == "\"CODE\"",
+            ".byte" | ".word" | ".res" => {
+                data_size += match name {
+                    ".res" => evaluate(&rest, &symbols, &scope).unwrap_or(0) as usize,
+                    ".word" => 2 * rest.split(',').count(),
+                    _ => byte_items(&rest).iter().map(|item| item.strip_prefix('"').map_or(1, |text| text.len() - 1)).sum(),
+                };
+                data.push((name.to_string(), rest, scope.clone()));
+            }
+            _ => code.push((name.to_lowercase(), rest, scope.clone())),
+        }
+    }
+    let data_start = CODE + 3 * code.len() as u32;
+    for (label, offset) in data_labels {
+        symbols.insert(label, (data_start as usize + offset) as i64);
+    }
+    let mut cpu = Cpu6502 { memory: vec![0; 0x10000], a: 0, x: 0, y: 0, s: 0xff, n: false, v: false, z: false, c: false, pc: CODE, steps: 0 };
+    let mut address = data_start as usize;
+    for (directive, rest, scope) in &data {
+        let error = |item: &str| RuntimeError { message: format!("Cannot work out {}", item), address: 0 };
+        match directive.as_str() {
+            ".res" => address += evaluate(rest, &symbols, scope).unwrap_or(0) as usize,
+            ".word" => for item in rest.split(',') {
+                let value = evaluate(item, &symbols, scope).ok_or_else(|| error(item))?;
+                cpu.memory[address] = value as u8;
+                cpu.memory[address + 1] = (value >> 8) as u8;
+                address += 2;
+            },
+            _ => for item in byte_items(rest) {
+                match item.strip_prefix('"') {
+                    Some(text) => for byte in text[..text.len() - 1].bytes() {
+                        cpu.memory[address] = byte;
+                        address += 1;
+                    },
+                    None => {
+                        cpu.memory[address] = evaluate(&item, &symbols, scope).ok_or_else(|| error(&item))? as u8;
+                        address += 1;
+                    }
+                }
+            },
+        }
+    }
+    let program = code.iter()
+        .map(|(op, operand, scope)| {
+            let error = || RuntimeError { message: format!("Cannot work out {} {}", op, operand), address: 0 };
+            let mode = if operand.is_empty() || operand == "a" || operand == "A" {
+                Mode::Implied
+            } else if let Some(value) = operand.strip_prefix('#') {
+                Mode::Immediate(evaluate(value, &symbols, scope).ok_or_else(error)? as u8)
+            } else {
+                let (value, index) = operand.rsplit_once(',').map_or((operand.as_str(), ""), |(value, index)| (value, index.trim()));
+                let value = evaluate(value, &symbols, scope).ok_or_else(error)? as u32 & 0xffff;
+                match index {
+                    "x" | "X" => Mode::AbsoluteX(value),
+                    "y" | "Y" => Mode::AbsoluteY(value),
+                    _ => Mode::Absolute(value),
+                }
+            };
+            Ok((op.as_str(), mode))
+        })
+        .collect::<Result<Vec<(&str, Mode)>, RuntimeError>>()?;
+    cpu.push(((EXIT - 1) >> 8) as u8);
+    cpu.push((EXIT - 1) as u8);
+    let fail = symbols.get("basic_fail").map(|&address| address as u32);
+    let mut failed = false;
+    let mut output = Vec::new();
+    while cpu.pc != EXIT {
+        failed |= Some(cpu.pc) == fail;
+        // The KERNAL routines do their work and return
+        if cpu.pc == CHROUT || cpu.pc == CHRIN {
+            if cpu.pc == CHROUT {
+                output.push(if cpu.a == 0x0d { b'\n' } else { cpu.a });
+            } else {
+                let mut byte = [0];
+                cpu.a = match input.read(&mut byte) {
+                    Ok(1) if byte[0] == b'\n' => 0x0d,
+                    Ok(1) => byte[0],
+                    _ => 0,
+                };
+                cpu.c = false;
+            }
+            cpu.pc = cpu.pull_address() + 1;
+            continue;
+        }
+        let index = (cpu.pc.wrapping_sub(CODE) / 3) as usize;
+        let Some(&(op, mode)) = program.get(index).filter(|_| cpu.pc >= CODE && (cpu.pc - CODE).is_multiple_of(3)) else {
+            return Err(cpu.error(format!("Jumped outside the program to ${:04x}", cpu.pc)));
+        };
+        if cpu.steps == STEP_LIMIT {
+            return Err(cpu.error(format!("Stopped after {} instructions", STEP_LIMIT)));
+        }
+        cpu.steps += 1;
+        let mut next = cpu.pc + 3;
+        let target = match mode {
+            Mode::Absolute(address) => address,
+            Mode::AbsoluteX(address) => (address + cpu.x as u32) & 0xffff,
+            Mode::AbsoluteY(address) => (address + cpu.y as u32) & 0xffff,
+            _ => 0,
+        };
+        let value = match mode {
+            Mode::Immediate(value) => value,
+            _ => cpu.memory[target as usize],
+        };
+        match op {
+            "lda" => cpu.a = cpu.flags(value),
+            "ldx" => cpu.x = cpu.flags(value),
+            "ldy" => cpu.y = cpu.flags(value),
+            "sta" => cpu.memory[target as usize] = cpu.a,
+            "stx" => cpu.memory[target as usize] = cpu.x,
+            "sty" => cpu.memory[target as usize] = cpu.y,
+            "tax" => cpu.x = cpu.flags(cpu.a),
+            "tay" => cpu.y = cpu.flags(cpu.a),
+            "txa" => cpu.a = cpu.flags(cpu.x),
+            "tya" => cpu.a = cpu.flags(cpu.y),
+            "tsx" => cpu.x = cpu.flags(cpu.s),
+            "txs" => cpu.s = cpu.x,
+            "pha" => cpu.push(cpu.a),
+            "pla" => {
+                let value = cpu.pull();
+                cpu.a = cpu.flags(value);
+            }
+            "adc" => cpu.add(value),
+            "sbc" => cpu.add(!value),
+            "and" => cpu.a = cpu.flags(cpu.a & value),
+            "ora" => cpu.a = cpu.flags(cpu.a | value),
+            "eor" => cpu.a = cpu.flags(cpu.a ^ value),
+            "cmp" | "cpx" | "cpy" => {
+                let register = match op {
+                    "cmp" => cpu.a,
+                    "cpx" => cpu.x,
+                    _     => cpu.y,
+                };
+                cpu.c = register >= value;
+                cpu.flags(register.wrapping_sub(value));
+            }
+            "inc" | "dec" => {
+                let value = if op == "inc" { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                cpu.memory[target as usize] = cpu.flags(value);
+            }
+            "inx" => cpu.x = cpu.flags(cpu.x.wrapping_add(1)),
+            "iny" => cpu.y = cpu.flags(cpu.y.wrapping_add(1)),
+            "dex" => cpu.x = cpu.flags(cpu.x.wrapping_sub(1)),
+            "dey" => cpu.y = cpu.flags(cpu.y.wrapping_sub(1)),
+            "asl" | "lsr" | "rol" | "ror" => {
+                let old = if mode == Mode::Implied { cpu.a } else { value };
+                let carry = cpu.c as u8;
+                let (value, out) = match op {
+                    "asl" => (old << 1, old & 0x80 != 0),
+                    "lsr" => (old >> 1, old & 1 != 0),
+                    "rol" => (old << 1 | carry, old & 0x80 != 0),
+                    _     => (old >> 1 | carry << 7, old & 1 != 0),
+                };
+                cpu.c = out;
+                cpu.flags(value);
+                match mode {
+                    Mode::Implied => cpu.a = value,
+                    _ => cpu.memory[target as usize] = value,
+                }
+            }
+            "clc" => cpu.c = false,
+            "sec" => cpu.c = true,
+            "clv" => cpu.v = false,
+            "cld" | "cli" | "sei" | "nop" => {}
+            "bcc" | "bcs" | "beq" | "bne" | "bmi" | "bpl" | "bvc" | "bvs" => {
+                let holds = match op {
+                    "bcc" => !cpu.c,
+                    "bcs" => cpu.c,
+                    "beq" => cpu.z,
+                    "bne" => !cpu.z,
+                    "bmi" => cpu.n,
+                    "bpl" => !cpu.n,
+                    "bvc" => !cpu.v,
+                    _     => cpu.v,
+                };
+                if holds {
+                    next = target;
+                }
+            }
+            "jmp" => next = target,
+            "jsr" => {
+                let back = cpu.pc + 2;
+                cpu.push((back >> 8) as u8);
+                cpu.push(back as u8);
+                next = target;
+            }
+            "rts" => next = cpu.pull_address() + 1,
+            _ => return Err(cpu.error(format!("Cannot run {} {}", op, code[index].1))),
+        }
+        cpu.pc = next;
+    }
+    Ok(Mos6502 { output, steps: cpu.steps, failed })
+}
+
+// The items of a .byte, keeping strings whole
+fn byte_items(text: &str) -> Vec<String> {
+    let mut items = vec![String::new()];
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                items.last_mut().unwrap().push(c);
+            }
+            ',' if !quoted => items.push(String::new()),
+            _ => items.last_mut().unwrap().push(c),
+        }
+    }
+    items.into_iter().map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect()
+}
+
+struct Cpu6502 {
+    memory: Vec<u8>,
+    a: u8,
+    x: u8,
+    y: u8,
+    // The stack pointer, into page 1
+    s: u8,
+    n: bool,
+    v: bool,
+    z: bool,
+    c: bool,
+    pc: u32,
+    steps: u64,
+}
+
+impl Cpu6502 {
+    fn error(&self, message: String) -> RuntimeError {
+        RuntimeError { message, address: self.pc as usize }
+    }
+
+    // Sets N and Z from a value, passing it through
+    fn flags(&mut self, value: u8) -> u8 {
+        self.n = value & 0x80 != 0;
+        self.z = value == 0;
+        value
+    }
+
+    // Adds with carry in binary, which sbc does with the value inverted
+    fn add(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + self.c as u16;
+        let result = sum as u8;
+        self.v = (!(self.a ^ value) & (self.a ^ result) & 0x80) != 0;
+        self.c = sum > 0xff;
+        self.a = self.flags(result);
+    }
+
+    fn push(&mut self, value: u8) {
+        self.memory[0x100 + self.s as usize] = value;
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        self.memory[0x100 + self.s as usize]
+    }
+
+    // The address jsr pushed, which is one before where to come back to
+    fn pull_address(&mut self) -> u32 {
+        let low = self.pull() as u32;
+        let high = self.pull() as u32;
+        high << 8 | low
+    }
+}
+
+// Reads numbers the way compiled programs do: anything up to the next digit
+// or `-` is skipped, and a number ends at the first character that isn't a
+// digit
+pub struct Numbers<R> {
+    bytes: std::io::Bytes<R>,
+}
+
+impl<R: std::io::BufRead> Numbers<R> {
+    pub fn new(reader: R) -> Numbers<R> {
+        Numbers { bytes: reader.bytes() }
+    }
+}
+
+impl<R: std::io::BufRead> Iterator for Numbers<R> {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        let mut negative = false;
+        let mut digit = loop {
+            match self.bytes.next()?.ok()? {
+                b'-' => negative = true,
+                byte @ b'0'..=b'9' => break byte,
+                _ => negative = false,
+            }
+        };
+        let mut value: i64 = 0;
+        loop {
+            value = value.wrapping_mul(10).wrapping_add((digit - b'0') as i64);
+            match self.bytes.next() {
+                Some(Ok(byte @ b'0'..=b'9')) => digit = byte,
+                _ => break,
+            }
+        }
+        Some(if negative { value.wrapping_neg() } else { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile, Options};
+
+    #[test]
+    fn output_before_a_failure_is_kept() {
+        let options = Options::default();
+        let output = compile("let a = 0\nprint 1\nprint 5\nprint 5 / a\n", &options).unwrap();
+        let mut machine = Machine::new(options.registers);
+        let error = run_on(&mut machine, &output.assembly, options.word_bits, &mut std::iter::empty()).unwrap_err();
+        assert_eq!(error.message, "Division by zero");
+        assert_eq!(machine.output, [1, 5]);
+    }
+
+    #[test]
+    fn failing_6502_programs_are_reported() {
+        let options = Options { target: crate::Target::Mos6502, word_bits: crate::mos6502::WORD_BITS, ..Options::default() };
+        let output = compile("let a = 0\nprint 1\nprint 5 / a\n", &options).unwrap();
+        let result = run_6502(&output.assembly, &mut std::io::empty()).unwrap();
+        assert!(result.failed);
+        assert_eq!(result.output, b"1\nDivision by zero\n");
+        let output = compile("print 1\n", &options).unwrap();
+        assert!(!run_6502(&output.assembly, &mut std::io::empty()).unwrap().failed);
+    }
+}
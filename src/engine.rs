@@ -0,0 +1,155 @@
+use crate::bytecode::Chunk;
+use crate::diagnostic::Diagnostic;
+use crate::emulator::RuntimeError;
+use crate::vm::Vm;
+use crate::{compile, Options, Target};
+
+// A host function, given its arguments in order. An error stops the script
+// with the message.
+pub type Function = Box<dyn FnMut(&[i32]) -> Result<i32, String>>;
+
+// Runs BASIC scripts inside a Rust program. The host registers functions
+// scripts can call, like `let x = clamp(y, 0, 10)` or `beep(440)`, and
+// variables scripts can use without declaring them. A script is compiled to
+// bytecode and run on the VM, and its variables keep their values from one
+// run to the next, where the host can read and change them by name.
+pub struct Engine {
+    // How scripts are compiled, which is as written unless opt_level says
+    // otherwise. Optimising can drop a script's own variables that it never
+    // reads, but never the host's. The target is always bytecode.
+    pub options: Options,
+    // Stops a run that goes on for longer, when set
+    pub step_limit: Option<u64>,
+    functions: Vec<(String, usize, Function)>,
+    globals: Vec<(String, i32)>,
+    vm: Option<Vm>,
+    // Which registered function each entry in the script's function table is
+    calls: Vec<usize>,
+}
+
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine { options: Options::default(), step_limit: None, functions: Vec::new(), globals: Vec::new(), vm: None, calls: Vec::new() }
+    }
+
+    // Lets scripts compiled from now on call `name` with this many
+    // arguments, replacing a function already registered with the name.
+    // A replaced function keeps its place, which the compiled script
+    // finds it by.
+    pub fn register(&mut self, name: &str, arguments: usize, function: impl FnMut(&[i32]) -> Result<i32, String> + 'static) {
+        assert!(arguments <= u8::MAX as usize, "A host function takes at most {} arguments", u8::MAX);
+        match self.functions.iter_mut().find(|(other, ..)| other == name) {
+            Some(old) => *old = (name.to_string(), arguments, Box::new(function)),
+            None => self.functions.push((name.to_string(), arguments, Box::new(function))),
+        }
+    }
+
+    // Gives scripts compiled from now on a variable they can use without
+    // declaring it, which starts at `value`
+    pub fn global(&mut self, name: &str, value: i32) {
+        match self.globals.iter_mut().find(|(other, _)| other == name) {
+            Some((_, old)) => *old = value,
+            None => self.globals.push((name.to_string(), value)),
+        }
+        if let Some(vm) = &mut self.vm {
+            vm.set(name, value);
+        }
+    }
+
+    // Compiles a script to run in place of the last one
+    pub fn compile(&mut self, source: &str) -> Result<(), Vec<Diagnostic>> {
+        let options = Options {
+            target: Target::Bytecode,
+            functions: self.functions.iter().map(|(name, arguments, _)| (name.clone(), *arguments)).collect(),
+            globals: self.globals.iter().map(|(name, _)| name.clone()).collect(),
+            ..self.options.clone()
+        };
+        let output = compile(source, &options)?;
+        // Bytecode always comes with its binary, which always decodes, so
+        // failing here is a bug in the compiler
+        let undecodable = |error: RuntimeError| vec![Diagnostic::new(format!("Generated bytecode does not decode: {}", error), 0, 0)];
+        let chunk = Chunk::decode(output.binary.as_deref().unwrap_or_default()).map_err(undecodable)?;
+        let mut vm = Vm::new(&chunk).map_err(undecodable)?;
+        for (name, value) in &self.globals {
+            vm.set(name, *value);
+        }
+        self.calls = chunk.functions.iter()
+            .map(|(name, _)| self.functions.iter().position(|(other, ..)| other == name).unwrap())
+            .collect();
+        self.vm = Some(vm);
+        Ok(())
+    }
+
+    // Runs the compiled script from the start, with variables as the last
+    // run left them
+    pub fn run(&mut self, input: &mut dyn Iterator<Item = i64>) -> Result<(), RuntimeError> {
+        let Engine { functions, vm, calls, step_limit, .. } = self;
+        let vm = vm.as_mut().ok_or_else(|| RuntimeError { message: "There is no script to run".to_string(), address: 0 })?;
+        vm.output.clear();
+        vm.steps = 0;
+        vm.step_limit = *step_limit;
+        vm.run(input, &mut |index, arguments| (functions[calls[index]].2)(arguments))
+    }
+
+    // What the last run printed
+    pub fn output(&self) -> &[i64] {
+        self.vm.as_ref().map_or(&[], |vm| &vm.output)
+    }
+
+    // A variable of the script, or of the host before there is a script
+    pub fn get(&self, name: &str) -> Option<i32> {
+        match &self.vm {
+            Some(vm) => vm.get(name),
+            None => self.globals.iter().find(|(other, _)| other == name).map(|(_, value)| *value),
+        }
+    }
+
+    // Changes a variable of the script for the next run, or of the host
+    // before there is a script, and says whether there is one
+    pub fn set(&mut self, name: &str, value: i32) -> bool {
+        match &mut self.vm {
+            Some(vm) => vm.set(name, value),
+            None => match self.globals.iter_mut().find(|(other, _)| other == name) {
+                Some((_, old)) => {
+                    *old = value;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_variables_can_be_set_before_there_is_a_script() {
+        let mut engine = Engine::new();
+        engine.global("speed", 3);
+        assert!(engine.set("speed", 5));
+        assert!(!engine.set("missing", 1));
+        assert_eq!(engine.get("speed"), Some(5));
+        engine.compile("print speed * 2").unwrap();
+        engine.run(&mut std::iter::empty()).unwrap();
+        assert_eq!(engine.output(), [10]);
+    }
+
+    #[test]
+    fn functions_registered_again_after_compiling_keep_their_place() {
+        let mut engine = Engine::new();
+        engine.register("a", 0, |_| Ok(1));
+        engine.register("b", 1, |arguments| Ok(arguments[0] * 2));
+        engine.compile("print b(21)\nprint a()").unwrap();
+        engine.register("a", 0, |_| Ok(3));
+        engine.run(&mut std::iter::empty()).unwrap();
+        assert_eq!(engine.output(), [42, 3]);
+    }
+}
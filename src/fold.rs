@@ -0,0 +1,180 @@
+use crate::ast::{BinaryOp, Expr, Program, Statement, StatementKind};
+
+// Constant folding and algebraic simplification of expressions. Arithmetic
+// wraps around at the word size, as it would on the target, and division by
+// a constant zero is left for the program to do at run time. With
+// `strength_reduce`, multiplying by a power of two becomes a left shift.
+pub fn fold(program: &mut Program, word_bits: u32, strength_reduce: bool) {
+    let folder = Folder { word_bits, strength_reduce };
+    folder.block(&mut program.statements);
+}
+
+struct Folder {
+    word_bits: u32,
+    strength_reduce: bool,
+}
+
+impl Folder {
+    fn block(&self, statements: &mut [Statement]) {
+        for statement in statements {
+            match &mut statement.kind {
+                StatementKind::Let { value, .. } | StatementKind::Assign { value, .. } | StatementKind::Print(value) => self.fold(value),
+                StatementKind::If { condition, body } | StatementKind::While { condition, body } => {
+                    self.fold(condition);
+                    self.block(body);
+                }
+                StatementKind::Call { arguments, .. } => arguments.iter_mut().for_each(|argument| self.fold(argument)),
+                _ => {}
+            }
+        }
+    }
+
+    fn fold(&self, expr: &mut Expr) {
+        match expr {
+            Expr::Binary(op, left, right) => {
+                self.fold(left);
+                self.fold(right);
+                if let Some(simpler) = self.simplify(*op, left, right) {
+                    *expr = simpler;
+                }
+            }
+            Expr::Call(_, arguments) => arguments.iter_mut().for_each(|argument| self.fold(argument)),
+            _ => {}
+        }
+    }
+
+    // Sign-extends the low word_bits bits of a value
+    fn wrap(&self, value: i64) -> i64 {
+        let shift = 64 - self.word_bits;
+        (value << shift) >> shift
+    }
+
+    fn evaluate(&self, op: BinaryOp, a: i64, b: i64) -> Option<i64> {
+        let truth = |holds: bool| if holds { -1 } else { 0 };
+        let value = match op {
+            BinaryOp::Add          => a.wrapping_add(b),
+            BinaryOp::Sub          => a.wrapping_sub(b),
+            BinaryOp::Mul          => a.wrapping_mul(b),
+            BinaryOp::Div          => a.checked_div(b)?,
+            BinaryOp::Mod          => a.checked_rem(b)?,
+            BinaryOp::Xor          => a ^ b,
+            BinaryOp::And          => a & b,
+            BinaryOp::Or           => a | b,
+            BinaryOp::Shl          => a.wrapping_shl(b as u32),
+            BinaryOp::Equal        => truth(a == b),
+            BinaryOp::NotEqual     => truth(a != b),
+            BinaryOp::Less         => truth(a < b),
+            BinaryOp::Greater      => truth(a > b),
+            BinaryOp::LessEqual    => truth(a <= b),
+            BinaryOp::GreaterEqual => truth(a >= b),
+        };
+        Some(self.wrap(value))
+    }
+
+    fn simplify(&self, op: BinaryOp, left: &Expr, right: &Expr) -> Option<Expr> {
+        match (left, right) {
+            (Expr::Number(a), Expr::Number(b)) => self.evaluate(op, *a, *b).map(Expr::Number),
+            (_, Expr::Number(b)) => match (op, *b) {
+                (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Or | BinaryOp::Xor | BinaryOp::Shl, 0) => Some(left.clone()),
+                (BinaryOp::Mul | BinaryOp::Div, 1) => Some(left.clone()),
+                (BinaryOp::And, -1) => Some(left.clone()),
+                // The host still has to be called for what else it does, and
+                // a division by zero still has to stop the program
+                (BinaryOp::Mul | BinaryOp::And, 0) if !left.calls() && !left.may_divide_by_zero() => Some(Expr::Number(0)),
+                // (x + a) + b and (x - a) + b become x + (a + b) or x - (a - b)
+                (BinaryOp::Add | BinaryOp::Sub, _) => match left {
+                    Expr::Binary(inner @ (BinaryOp::Add | BinaryOp::Sub), x, a) => match **a {
+                        Expr::Number(a) => {
+                            let b = if op == *inner { *b } else { self.wrap(b.wrapping_neg()) };
+                            let sum = self.evaluate(BinaryOp::Add, a, b)?;
+                            self.simplify(*inner, x, &Expr::Number(sum)).or_else(|| Some(Expr::Binary(*inner, x.clone(), Box::new(Expr::Number(sum)))))
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                },
+                (BinaryOp::Mul, b) if self.strength_reduce && b > 0 && b & (b - 1) == 0 => {
+                    Some(Expr::Binary(BinaryOp::Shl, Box::new(left.clone()), Box::new(Expr::Number(b.trailing_zeros() as i64))))
+                }
+                _ => None,
+            },
+            (Expr::Number(a), _) => match (op, *a) {
+                (BinaryOp::Add | BinaryOp::Or | BinaryOp::Xor, 0) => Some(right.clone()),
+                (BinaryOp::Mul, 1) => Some(right.clone()),
+                (BinaryOp::And, -1) => Some(right.clone()),
+                (BinaryOp::Mul | BinaryOp::And, 0) if !right.calls() && !right.may_divide_by_zero() => Some(Expr::Number(0)),
+                (BinaryOp::Mul, a) if self.strength_reduce && a > 0 && a & (a - 1) == 0 => {
+                    Some(Expr::Binary(BinaryOp::Shl, Box::new(right.clone()), Box::new(Expr::Number(a.trailing_zeros() as i64))))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Options};
+
+    // The value each `let` of the program ends up with
+    fn folded(source: &str, word_bits: u32, strength_reduce: bool) -> Vec<Expr> {
+        let mut program = parse(source, &Options { word_bits, ..Options::default() }).unwrap();
+        fold(&mut program, word_bits, strength_reduce);
+        program.statements.into_iter().filter_map(|statement| match statement.kind {
+            StatementKind::Let { value, .. } => Some(value),
+            _ => None,
+        }).collect()
+    }
+
+    fn number(value: i64) -> Box<Expr> {
+        Box::new(Expr::Number(value))
+    }
+
+    fn variable(name: &str) -> Box<Expr> {
+        Box::new(Expr::Variable(name.to_string()))
+    }
+
+    #[test]
+    fn constants_fold_and_wrap_at_the_word_size() {
+        assert_eq!(folded("let a = 2 + 3 * 4\nlet b = 7 % 4 - 10", 32, false), [Expr::Number(20), Expr::Number(-7)]);
+        assert_eq!(folded("let a = 127 + 1", 8, false), [Expr::Number(-128)]);
+        assert_eq!(folded("let a = 1 / 0", 32, false), [Expr::Binary(BinaryOp::Div, number(1), number(0))]);
+    }
+
+    #[test]
+    fn identities_drop_the_constant() {
+        let x = "let x = 3\n";
+        assert_eq!(folded(&format!("{}let a = x + 0\nlet b = 1 * x\nlet c = x * 0", x), 32, false)[1..], [*variable("x"), *variable("x"), Expr::Number(0)]);
+        assert_eq!(folded(&format!("{}let a = x + 2 + 3\nlet b = x - 2 + 3", x), 32, false)[1..], [
+            Expr::Binary(BinaryOp::Add, variable("x"), number(5)),
+            Expr::Binary(BinaryOp::Sub, variable("x"), number(-1)),
+        ]);
+    }
+
+    #[test]
+    fn multiplying_by_zero_keeps_a_division_that_may_fail() {
+        let values = folded("let x = 3\nlet a = 5 / x * 0\nlet b = 0 * x % 0\nlet c = x / 2 * 0", 32, false);
+        assert_eq!(values[1], Expr::Binary(BinaryOp::Mul, Box::new(Expr::Binary(BinaryOp::Div, number(5), variable("x"))), number(0)));
+        assert!(matches!(values[2], Expr::Binary(BinaryOp::Mod, ..)));
+        assert_eq!(values[3], Expr::Number(0));
+    }
+
+    #[test]
+    fn negating_the_lowest_number_wraps() {
+        let values = folded("let x = 3\nlet a = x - 1 + &H8000000000000000", 64, false);
+        assert_eq!(values[1], Expr::Binary(BinaryOp::Sub, variable("x"), number(i64::MIN + 1)));
+    }
+
+    #[test]
+    fn strength_reduction_shifts_by_powers_of_two() {
+        let source = "let x = 3\nlet a = x * 8\nlet b = 4 * x\nlet c = x * 6";
+        assert_eq!(folded(source, 32, true)[1..], [
+            Expr::Binary(BinaryOp::Shl, variable("x"), number(3)),
+            Expr::Binary(BinaryOp::Shl, variable("x"), number(2)),
+            Expr::Binary(BinaryOp::Mul, variable("x"), number(6)),
+        ]);
+        assert_eq!(folded(source, 32, false)[1], Expr::Binary(BinaryOp::Mul, variable("x"), number(8)));
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::ast::{BinaryOp, Expr, Program, Statement, StatementKind};
+use crate::diagnostic::Diagnostic;
+
+// Three-address code organised into basic blocks. Each block is a straight
+// run of instructions ending in a terminator that says where control goes
+// next, so the blocks and their terminators form the control-flow graph of
+// the program.
+
+pub type BlockId = usize;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Value {
+    Const(i64),
+    Var(String),
+    // Holds an intermediate result of an expression. Every temporary is
+    // assigned once and used once, later in the same block.
+    Temp(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inst {
+    Copy { dest: Value, src: Value },
+    // Comparisons give -1 (true) or 0 (false)
+    Binary { dest: Value, op: BinaryOp, left: Value, right: Value },
+    Print(Value),
+    // Reads a number into a variable. Reading has an effect of its own, so
+    // this stays even if the variable is never used.
+    Input(Value),
+    // Calls a host function, keeping what it gives back in `dest` if there
+    // is one. Like input, the call stays even if nothing reads it.
+    Call { dest: Option<Value>, name: String, arguments: Vec<Value> },
+    Comment(String),
+    // Where in the source the code that follows comes from, which is the
+    // statement it was lowered from. It makes no code itself.
+    Position { line: usize, column: usize },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    // Goes to `then` when `left op right` holds and to `otherwise` if not
+    Branch { op: BinaryOp, left: Value, right: Value, then: BlockId, otherwise: BlockId },
+    // A `gosub`: runs `target` until it returns, then carries on at `next`
+    Call { target: BlockId, next: BlockId },
+    Return,
+    // The end of the program
+    Halt,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+    // Where the first statement lowered into this block is, if any
+    pub source: Option<(usize, usize)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    // Where it is declared
+    pub line: usize,
+    pub column: usize,
+    // Given by the host, which can read it once the program ends
+    pub global: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    // The order blocks are laid out in, which follows the source. Block 0
+    // comes first and is where the program starts.
+    pub order: Vec<BlockId>,
+    // Variables in the order they are declared
+    pub variables: Vec<Variable>,
+}
+
+impl Inst {
+    pub fn dest(&self) -> Option<&Value> {
+        match self {
+            Inst::Copy { dest, .. } | Inst::Binary { dest, .. } | Inst::Input(dest) => Some(dest),
+            Inst::Call { dest, .. } => dest.as_ref(),
+            Inst::Print(_) | Inst::Comment(_) | Inst::Position { .. } => None,
+        }
+    }
+
+    pub fn uses(&self) -> Vec<&Value> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Print(src) => vec![src],
+            Inst::Binary { left, right, .. } => vec![left, right],
+            Inst::Call { arguments, .. } => arguments.iter().collect(),
+            Inst::Input(_) | Inst::Comment(_) | Inst::Position { .. } => vec![],
+        }
+    }
+
+    // Replaces every use of `from` with `to`
+    pub fn replace(&mut self, from: &Value, to: &Value) {
+        match self {
+            Inst::Copy { src, .. } | Inst::Print(src) => replace(src, from, to),
+            Inst::Binary { left, right, .. } => {
+                replace(left, from, to);
+                replace(right, from, to);
+            }
+            Inst::Call { arguments, .. } => arguments.iter_mut().for_each(|argument| replace(argument, from, to)),
+            Inst::Input(_) | Inst::Comment(_) | Inst::Position { .. } => {}
+        }
+    }
+}
+
+fn replace(value: &mut Value, from: &Value, to: &Value) {
+    if value == from {
+        *value = to.clone();
+    }
+}
+
+impl Terminator {
+    pub fn replace(&mut self, from: &Value, to: &Value) {
+        if let Terminator::Branch { left, right, .. } = self {
+            replace(left, from, to);
+            replace(right, from, to);
+        }
+    }
+
+    // Sends every edge to `from` to `to` instead
+    pub fn retarget(&mut self, from: BlockId, to: BlockId) {
+        let edges = match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch { then, otherwise, .. } => vec![then, otherwise],
+            Terminator::Call { target, next } => vec![target, next],
+            Terminator::Return | Terminator::Halt => vec![],
+        };
+        for edge in edges {
+            if *edge == from {
+                *edge = to;
+            }
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then, otherwise, .. } => vec![*then, *otherwise],
+            Terminator::Call { target, next } => vec![*target, *next],
+            Terminator::Return | Terminator::Halt => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<&Value> {
+        match self {
+            Terminator::Branch { left, right, .. } => vec![left, right],
+            _ => vec![],
+        }
+    }
+}
+
+impl Block {
+    // Whether the block makes no code before its terminator
+    pub fn is_empty(&self) -> bool {
+        self.insts.iter().all(|inst| matches!(inst, Inst::Position { .. }))
+    }
+}
+
+impl Cfg {
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for &block in &self.order {
+            for successor in self.blocks[block].terminator.successors() {
+                predecessors[successor].push(block);
+            }
+        }
+        predecessors
+    }
+
+    // Which blocks can be reached from the start of the program. A `return`
+    // goes back to the block after some `gosub`, which is already a
+    // successor of that call.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut work = vec![self.order[0]];
+        while let Some(block) = work.pop() {
+            if !reachable[block] {
+                reachable[block] = true;
+                work.extend(self.blocks[block].terminator.successors());
+            }
+        }
+        reachable
+    }
+
+    // How many temporaries there are, or one more than the highest numbered
+    pub fn temps(&self) -> usize {
+        self.blocks.iter()
+            .flat_map(|block| block.insts.iter().filter_map(Inst::dest))
+            .filter_map(|dest| match dest {
+                Value::Temp(temp) => Some(temp + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Numbers the temporaries from 0 so that ones never live at the same
+    // time share a number, and says how many numbers that takes. A
+    // temporary is live from where it is assigned to its last use in the
+    // block, and none is live from one block to the next. One that is
+    // assigned doesn't share with the ones its instruction reads, so a
+    // target can write it while still reading them.
+    pub fn temp_slots(&self) -> (HashMap<usize, usize>, usize) {
+        let mut slots = HashMap::new();
+        let mut count = 0;
+        for block in &self.blocks {
+            let mentions: Vec<(Vec<&Value>, Option<&Value>)> = block.insts.iter()
+                .map(|inst| (inst.uses(), inst.dest()))
+                .chain(std::iter::once((block.terminator.uses(), None)))
+                .collect();
+            let mut last = HashMap::new();
+            for (index, (uses, _)) in mentions.iter().enumerate() {
+                for value in uses {
+                    if let Value::Temp(temp) = value {
+                        last.insert(*temp, index);
+                    }
+                }
+            }
+            let mut free: Vec<usize> = Vec::new();
+            let mut used = 0;
+            for (index, (uses, dest)) in mentions.iter().enumerate() {
+                if let Some(Value::Temp(temp)) = dest {
+                    let slot = free.pop().unwrap_or_else(|| {
+                        used += 1;
+                        used - 1
+                    });
+                    slots.insert(*temp, slot);
+                }
+                for value in uses {
+                    if let Value::Temp(temp) = value {
+                        if last.get(temp) == Some(&index) {
+                            last.remove(temp);
+                            free.extend(slots.get(temp));
+                        }
+                    }
+                }
+            }
+            count = count.max(used);
+        }
+        (slots, count)
+    }
+}
+
+// Lowers a parsed program to basic blocks. A `goto` or `gosub` to a label
+// that is never defined is reported here.
+pub fn lower(program: &Program) -> Result<Cfg, Vec<Diagnostic>> {
+    let mut lowering = Lowering {
+        cfg: Cfg { blocks: Vec::new(), order: Vec::new(), variables: Vec::new() },
+        current: 0,
+        temps: 0,
+        labels: HashMap::new(),
+        defined: HashSet::new(),
+        references: Vec::new(),
+    };
+    for name in &program.globals {
+        lowering.cfg.variables.push(Variable { name: name.clone(), line: 0, column: 0, global: true });
+    }
+    let entry = lowering.new_block();
+    lowering.start(entry);
+    lowering.block(&program.statements);
+    lowering.finish(Terminator::Halt);
+    let diagnostics: Vec<Diagnostic> = lowering.references.iter()
+        .filter(|(label, _, _)| !lowering.defined.contains(label))
+        .map(|(label, line, column)| Diagnostic::new(format!("Label {} does not exist", label), *line, *column))
+        .collect();
+    if diagnostics.is_empty() {
+        Ok(lowering.cfg)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+struct Lowering {
+    cfg: Cfg,
+    current: BlockId,
+    temps: usize,
+    labels: HashMap<String, BlockId>,
+    defined: HashSet<String>,
+    references: Vec<(String, usize, usize)>,
+}
+
+impl Lowering {
+    fn new_block(&mut self) -> BlockId {
+        self.cfg.blocks.push(Block { insts: Vec::new(), terminator: Terminator::Halt, source: None });
+        self.cfg.blocks.len() - 1
+    }
+
+    fn start(&mut self, block: BlockId) {
+        self.cfg.order.push(block);
+        self.current = block;
+    }
+
+    fn finish(&mut self, terminator: Terminator) {
+        self.cfg.blocks[self.current].terminator = terminator;
+    }
+
+    // Ends the current block and carries on in a new one that nothing jumps
+    // to yet, for the statements after a `goto`, `return` or `end`
+    fn finish_and_continue(&mut self, terminator: Terminator) {
+        self.finish(terminator);
+        let next = self.new_block();
+        self.start(next);
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.cfg.blocks[self.current].insts.push(inst);
+    }
+
+    fn label_block(&mut self, label: &str) -> BlockId {
+        match self.labels.get(label) {
+            Some(&block) => block,
+            None => {
+                let block = self.new_block();
+                self.labels.insert(label.to_string(), block);
+                block
+            }
+        }
+    }
+
+    fn reference(&mut self, label: &str, statement: &Statement) -> BlockId {
+        self.references.push((label.to_string(), statement.line, statement.column));
+        self.label_block(label)
+    }
+
+    fn new_temp(&mut self) -> Value {
+        self.temps += 1;
+        Value::Temp(self.temps - 1)
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        if !matches!(statement.kind, StatementKind::Label(_) | StatementKind::Comment(_)) {
+            let block = &mut self.cfg.blocks[self.current];
+            if block.source.is_none() {
+                block.source = Some((statement.line, statement.column));
+            }
+            self.emit(Inst::Position { line: statement.line, column: statement.column });
+        }
+        match &statement.kind {
+            StatementKind::Let { name, value } | StatementKind::Assign { name, value } => {
+                if matches!(statement.kind, StatementKind::Let { .. }) {
+                    self.cfg.variables.push(Variable { name: name.clone(), line: statement.line, column: statement.column, global: false });
+                }
+                self.assign(Value::Var(name.clone()), value);
+            }
+            StatementKind::If { condition, body } => {
+                let then = self.new_block();
+                let end_of_if = self.new_block();
+                self.branch(condition, then, end_of_if);
+                self.start(then);
+                self.block(body);
+                self.finish(Terminator::Jump(end_of_if));
+                self.start(end_of_if);
+            }
+            StatementKind::While { condition, body } => {
+                let condition_loop = self.new_block();
+                let body_of_while = self.new_block();
+                let end_of_while = self.new_block();
+                self.finish(Terminator::Jump(condition_loop));
+                self.start(condition_loop);
+                self.emit(Inst::Position { line: statement.line, column: statement.column });
+                self.branch(condition, body_of_while, end_of_while);
+                self.start(body_of_while);
+                self.block(body);
+                // Going round again belongs to the loop, not its last statement
+                self.emit(Inst::Position { line: statement.line, column: statement.column });
+                self.finish(Terminator::Jump(condition_loop));
+                self.start(end_of_while);
+            }
+            StatementKind::Label(label) => {
+                let block = self.label_block(label);
+                self.defined.insert(label.clone());
+                self.finish(Terminator::Jump(block));
+                self.start(block);
+            }
+            StatementKind::Goto(label) => {
+                let target = self.reference(label, statement);
+                self.finish_and_continue(Terminator::Jump(target));
+            }
+            StatementKind::Gosub(label) => {
+                let target = self.reference(label, statement);
+                let next = self.new_block();
+                self.finish(Terminator::Call { target, next });
+                self.start(next);
+            }
+            StatementKind::Return => self.finish_and_continue(Terminator::Return),
+            StatementKind::Print(value) => {
+                let value = self.value(value);
+                self.emit(Inst::Print(value));
+            }
+            StatementKind::Input(name) => self.emit(Inst::Input(Value::Var(name.clone()))),
+            StatementKind::Call { name, arguments } => self.call(None, name, arguments),
+            StatementKind::End => self.finish_and_continue(Terminator::Halt),
+            StatementKind::Comment(text) => self.emit(Inst::Comment(text.clone())),
+        }
+    }
+
+    fn branch(&mut self, condition: &Expr, then: BlockId, otherwise: BlockId) {
+        let (op, left, right) = match condition {
+            Expr::Binary(op, left, right) if op.is_relation() => (*op, self.value(left), self.value(right)),
+            _ => (BinaryOp::NotEqual, self.value(condition), Value::Const(0)),
+        };
+        self.finish(Terminator::Branch { op, left, right, then, otherwise });
+    }
+
+    // Evaluates an expression straight into `dest`
+    fn assign(&mut self, dest: Value, expr: &Expr) {
+        match expr {
+            Expr::Binary(op, left, right) => {
+                let left = self.value(left);
+                let right = self.value(right);
+                self.emit(Inst::Binary { dest, op: *op, left, right });
+            }
+            Expr::Call(name, arguments) => self.call(Some(dest), name, arguments),
+            _ => {
+                let src = self.value(expr);
+                self.emit(Inst::Copy { dest, src });
+            }
+        }
+    }
+
+    // Evaluates an expression to a value an instruction can use
+    fn value(&mut self, expr: &Expr) -> Value {
+        match expr {
+            Expr::Number(value) => Value::Const(*value),
+            Expr::Variable(name) => Value::Var(name.clone()),
+            Expr::Binary(op, left, right) => {
+                let left = self.value(left);
+                let right = self.value(right);
+                let temp = self.new_temp();
+                self.emit(Inst::Binary { dest: temp.clone(), op: *op, left, right });
+                temp
+            }
+            Expr::Call(name, arguments) => {
+                let temp = self.new_temp();
+                self.call(Some(temp.clone()), name, arguments);
+                temp
+            }
+        }
+    }
+
+    // Works out the arguments in order and then calls the host
+    fn call(&mut self, dest: Option<Value>, name: &str, arguments: &[Expr]) {
+        let arguments = arguments.iter().map(|argument| self.value(argument)).collect();
+        self.emit(Inst::Call { dest, name: name.to_string(), arguments });
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Const(value) => write!(f, "{}", value),
+            Value::Var(name) => write!(f, "{}", name),
+            Value::Temp(temp) => write!(f, "%{}", temp),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inst::Copy { dest, src } => write!(f, "{} = {}", dest, src),
+            Inst::Binary { dest, op, left, right } => write!(f, "{} = {} {} {}", dest, left, op.symbol(), right),
+            Inst::Print(value) => write!(f, "print {}", value),
+            Inst::Input(dest) => write!(f, "input {}", dest),
+            Inst::Call { dest, name, arguments } => {
+                if let Some(dest) = dest {
+                    write!(f, "{} = ", dest)?;
+                }
+                let arguments: Vec<String> = arguments.iter().map(Value::to_string).collect();
+                write!(f, "{}({})", name, arguments.join(", "))
+            }
+            Inst::Comment(text) => write!(f, "; {}", text),
+            Inst::Position { line, column } => write!(f, "at {}:{}", line, column),
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jump b{}", target),
+            Terminator::Branch { op, left, right, then, otherwise } => {
+                write!(f, "if {} {} {} then b{} else b{}", left, op.symbol(), right, then, otherwise)
+            }
+            Terminator::Call { target, next } => write!(f, "call b{} then b{}", target, next),
+            Terminator::Return => write!(f, "return"),
+            Terminator::Halt => write!(f, "halt"),
+        }
+    }
+}
+
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &block in &self.order {
+            writeln!(f, "b{}:", block)?;
+            // Positions are left out, since they make no code
+            for inst in self.blocks[block].insts.iter().filter(|inst| !matches!(inst, Inst::Position { .. })) {
+                writeln!(f, "    {}", inst)?;
+            }
+            writeln!(f, "    {}", self.blocks[block].terminator)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Options};
+
+    fn cfg(source: &str) -> Cfg {
+        lower(&parse(source, &Options::default()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn expressions_become_three_address_code() {
+        let cfg = cfg("let a = 1 + 2 * 3\nlet b = a\n");
+        assert_eq!(cfg.blocks[0].insts, [
+            Inst::Position { line: 1, column: 1 },
+            Inst::Binary { dest: Value::Temp(0), op: BinaryOp::Add, left: Value::Const(1), right: Value::Const(2) },
+            Inst::Binary { dest: Value::Var(String::from("a")), op: BinaryOp::Mul, left: Value::Temp(0), right: Value::Const(3) },
+            Inst::Position { line: 2, column: 1 },
+            Inst::Copy { dest: Value::Var(String::from("b")), src: Value::Var(String::from("a")) },
+        ]);
+        assert_eq!(cfg.variables.iter().map(|variable| (variable.name.as_str(), variable.line)).collect::<Vec<_>>(), [("a", 1), ("b", 2)]);
+    }
+
+    #[test]
+    fn ifs_and_whiles_branch_between_blocks() {
+        let cfg = cfg("let a = 1 + 2 * 3\nlet b = a\nif a < b then\n' yes\nb = 0\nend if\nwhile b do\nb = b - 1\nend while\n");
+        let expected = "\
+b0:
+    %0 = 1 + 2
+    a = %0 * 3
+    b = a
+    if a < b then b1 else b2
+b1:
+    ; yes
+    b = 0
+    jump b2
+b2:
+    jump b3
+b3:
+    if b != 0 then b4 else b5
+b4:
+    b = b - 1
+    jump b3
+b5:
+    halt
+";
+        assert_eq!(cfg.to_string(), expected);
+        assert_eq!(cfg.predecessors()[3], [2, 4]);
+    }
+
+    #[test]
+    fn code_after_a_goto_is_unreachable() {
+        let cfg = cfg("let a = 1\ngoto skip\na = 2\nlabel skip\ngosub sub\nend\nlabel sub\nreturn\n");
+        assert_eq!(cfg.order, [0, 2, 1, 4, 5, 3, 6]);
+        assert_eq!(cfg.blocks[1].terminator, Terminator::Call { target: 3, next: 4 });
+        assert_eq!(cfg.reachable(), [true, true, false, true, true, false, false]);
+    }
+
+    #[test]
+    fn jumps_to_missing_labels_are_reported() {
+        let diagnostics = lower(&parse("let a = 1\ngoto nowhere\n", &Options::default()).unwrap()).unwrap_err();
+        assert_eq!((diagnostics[0].message.as_str(), diagnostics[0].line, diagnostics[0].column), ("Label nowhere does not exist", 2, 1));
+    }
+
+    #[test]
+    fn temporaries_share_slots_when_never_live_at_once() {
+        let source = "let x = 1\nprint x * 2 + x * 3\nprint x * 4 + x * 5\nif x * 6 < x * 7 then\nprint x\nend if\n";
+        let cfg = crate::lower(source, &Options::default()).unwrap();
+        let (slots, count) = cfg.temp_slots();
+        assert_eq!((slots.len(), count), (8, 2));
+        for block in &cfg.blocks {
+            for inst in &block.insts {
+                if let Some(Value::Temp(dest)) = inst.dest() {
+                    for value in inst.uses() {
+                        if let Value::Temp(temp) = value {
+                            assert_ne!(slots[dest], slots[temp]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,567 @@
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+#[allow(dead_code, non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum TokenType {
+    BLANK        = -2,
+    EOF          = -1,
+    NEWLINE      = 0,
+    NUMBER       = 1,
+    IDENT        = 2,
+    STRING       = 3,
+    PLUS         = 101,
+    MINUS        = 102,
+    STAR         = 103,
+    SLASH        = 104,
+    PERCENT      = 105,
+    CARET        = 106,
+    AMPERSAND    = 107,
+    PIPE         = 108,
+    EQUAL        = 201,
+    EQUAL_EQUAL  = 202,
+    NOT_EQUAL    = 203,
+    LESS         = 204,
+    LESS_EQUAL   = 205,
+    GREATER      = 206,
+    GREATER_EQUAL= 207,
+    NOT          = 208,
+    LEFT_PAREN   = 301,
+    RIGHT_PAREN  = 302,
+    COLON        = 303,
+    COMMA        = 304,
+    LET          = 401,
+    IF           = 402,
+    ELSE         = 403,
+    WHILE        = 404,
+    FOR          = 405,
+    END          = 406,
+    THEN         = 407,
+    DO           = 408,
+    LABEL        = 409,
+    GOTO         = 410,
+    GOSUB        = 411,
+    RETURN       = 412,
+    PRINT        = 413,
+    INPUT        = 414,
+    COMMENT      = 501,
+}
+
+impl TokenType {
+    pub fn display(&self) -> String {
+        match self {
+            TokenType::BLANK         => String::from("BLANK"),
+            TokenType::EOF           => String::from("EOF"),
+            TokenType::NEWLINE       => String::from("NEWLINE"),
+            TokenType::NUMBER        => String::from("NUMBER"),
+            TokenType::IDENT         => String::from("IDENT"),
+            TokenType::STRING        => String::from("STRING"),
+            TokenType::PLUS          => String::from("PLUS"),
+            TokenType::MINUS         => String::from("MINUS"),
+            TokenType::STAR          => String::from("STAR"),
+            TokenType::SLASH         => String::from("SLASH"),
+            TokenType::PERCENT       => String::from("PERCENT"),
+            TokenType::CARET         => String::from("CARET"),
+            TokenType::AMPERSAND     => String::from("AMPERSAND"),
+            TokenType::PIPE          => String::from("PIPE"),
+            TokenType::EQUAL         => String::from("EQUAL"),
+            TokenType::EQUAL_EQUAL   => String::from("EQUAL_EQUAL"),
+            TokenType::NOT_EQUAL     => String::from("NOT_EQUAL"),
+            TokenType::LESS          => String::from("LESS"),
+            TokenType::LESS_EQUAL    => String::from("LESS_EQUAL"),
+            TokenType::GREATER       => String::from("GREATER"),
+            TokenType::GREATER_EQUAL => String::from("GREATER_EQUAL"),
+            TokenType::NOT           => String::from("NOT"),
+            TokenType::LEFT_PAREN    => String::from("LEFT_PAREN"),
+            TokenType::RIGHT_PAREN   => String::from("RIGHT_PAREN"),
+            TokenType::COLON         => String::from("COLON"),
+            TokenType::COMMA         => String::from("COMMA"),
+            TokenType::LET           => String::from("LET"),
+            TokenType::IF            => String::from("IF"),
+            TokenType::ELSE          => String::from("ELSE"),
+            TokenType::WHILE         => String::from("WHILE"),
+            TokenType::FOR           => String::from("FOR"),
+            TokenType::END           => String::from("END"),
+            TokenType::THEN          => String::from("THEN"),
+            TokenType::DO            => String::from("DO"),
+            TokenType::LABEL         => String::from("LABEL"),
+            TokenType::GOTO          => String::from("GOTO"),
+            TokenType::GOSUB         => String::from("GOSUB"),
+            TokenType::RETURN        => String::from("RETURN"),
+            TokenType::PRINT         => String::from("PRINT"),
+            TokenType::INPUT         => String::from("INPUT"),
+            TokenType::COMMENT       => String::from("COMMENT"),
+        }
+    }
+}
+
+// A token and where it starts in the source (line and column from 1)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub kind: TokenType,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Token {
+    pub fn new(text: String, kind: TokenType) -> Token {
+        Token { text, kind, line: 0, column: 0 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at line {}, column {}", self.message, self.line, self.column)
+    }
+}
+
+impl std::error::Error for LexError {}
+
+// How identifiers are spelled once lexed. Keywords are always matched
+// regardless of case, so `LET`, `Let` and `let` are the same token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Case {
+    Lower,
+    Upper,
+    Preserve,
+}
+
+impl Case {
+    fn apply(&self, text: &str) -> String {
+        match self {
+            Case::Lower    => text.to_lowercase(),
+            Case::Upper    => text.to_uppercase(),
+            Case::Preserve => text.to_string(),
+        }
+    }
+}
+
+// Integers on the target are signed words of this many bits
+pub const WORD_BITS: u32 = 32;
+
+// The lexer walks the source as a byte cursor: `pos` is the byte offset of
+// `char`, so every step is constant time and multi-byte characters in
+// strings and comments are kept whole. As an iterator it yields every token
+// up to (but not including) EOF, and stops after the first error.
+pub struct Lexer {
+    source: String,
+    pos: usize,
+    char: char,
+    line: usize,
+    column: usize,
+    case: Case,
+    word_bits: u32,
+    done: bool,
+    // Whether the last token could end an operand, in which case `&` is
+    // the operator even when a letter follows it, so `a&h1` is `a & h1`
+    after_operand: bool,
+}
+
+impl Lexer {
+    pub fn new(source: String) -> Lexer {
+        Lexer::with_options(source, Case::Lower, WORD_BITS)
+    }
+
+    pub fn with_options(source: String, case: Case, word_bits: u32) -> Lexer {
+        let mut lexer = Lexer {
+            source, 
+            pos: 0, 
+            char: '\0',
+            line: 1,
+            column: 1,
+            case,
+            word_bits,
+            done: false,
+            after_operand: false,
+        };
+        lexer.init();
+        lexer
+    }
+
+    fn init(&mut self) {
+        self.pos = 0;
+        self.char = self.source.chars().next().unwrap_or('\0');
+    }
+
+    fn advance(&mut self) {
+        if self.pos < self.source.len() {
+            self.pos += self.char.len_utf8();
+            if self.char == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.char = self.source[self.pos..].chars().next().unwrap_or('\0');
+    }
+
+    fn peek(&self) -> char {
+        self.peek_at(1)
+    }
+
+    fn peek_at(&self, offset: usize) -> char {
+        self.source[self.pos..].chars().nth(offset).unwrap_or('\0')
+    }
+
+    fn error(&self, message: String, line: usize, column: usize) -> LexError {
+        LexError { message, line, column }
+    }
+
+    // Strings may not span lines. A quote is written either doubled (`""`)
+    // or escaped (`\"`), and `\n`, `\t`, `\0` and `\\` are also understood.
+    fn string(&mut self) -> Result<String, LexError> {
+        let (line, column) = (self.line, self.column);
+        let mut text = String::new();
+        self.advance();
+        loop {
+            match self.char {
+                '"' if self.peek() == '"' => {
+                    text.push('"');
+                    self.advance();
+                }
+                '"' => break,
+                '\\' => {
+                    self.advance();
+                    match self.char {
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        '0' => text.push('\0'),
+                        '\\' | '"' => text.push(self.char),
+                        '\0' | '\n' => return Err(self.error("Unterminated string".to_string(), line, column)),
+                        _ => return Err(self.error(format!("Unknown escape '\\{}' in string", self.char), self.line, self.column - 1)),
+                    }
+                }
+                '\0' | '\n' => return Err(self.error("Unterminated string".to_string(), line, column)),
+                c => text.push(c),
+            }
+            self.advance();
+        }
+        self.advance();
+        Ok(text)
+    }
+
+    fn radix_prefix(&self) -> Option<u32> {
+        let radix = match (self.char, self.peek().to_ascii_lowercase()) {
+            ('0', 'x') => 16,
+            ('&', _) if self.after_operand => return None,
+            ('&', 'h') => 16,
+            ('&', 'b') => 2,
+            ('&', 'o') => 8,
+            _ => return None,
+        };
+        if self.peek_at(2).is_digit(radix) { Some(radix) } else { None }
+    }
+
+    // Reads the digits of a number and returns it in decimal. Decimal numbers
+    // must fit in a signed word, while hex, binary and octal may use the whole
+    // word and are read as two's complement so `&HFFFFFFFF` is -1.
+    fn number(&mut self, radix: u32) -> Result<String, LexError> {
+        let (line, column) = (self.line, self.column);
+        let mut digits = String::new();
+        while self.char.is_digit(radix) {
+            digits.push(self.char);
+            self.advance();
+        }
+        let limit: i128 = if radix == 10 { 1 << (self.word_bits - 1) } else { 1 << self.word_bits };
+        match i128::from_str_radix(&digits, radix) {
+            Ok(value) if value < limit => {
+                if value >= 1 << (self.word_bits - 1) {
+                    Ok((value - (1 << self.word_bits)).to_string())
+                } else {
+                    Ok(value.to_string())
+                }
+            }
+            _ => Err(self.error(format!("Number {} does not fit in a {}-bit word", digits, self.word_bits), line, column)),
+        }
+    }
+
+    // Newlines end statements, so they are tokens rather than blanks
+    fn skip_blank(&mut self) {
+        while self.char.is_whitespace() && self.char != '\n' {
+            self.advance();
+        }
+    }
+
+    // Reads the rest of the line up to (but not including) the newline
+    fn rest_of_line(&mut self) -> String {
+        let mut text = String::new();
+        while self.char != '\n' && self.char != '\0' {
+            text.push(self.char);
+            self.advance();
+        }
+        text.trim().to_string()
+    }
+
+    pub fn get_token(&mut self) -> Result<Token, LexError> {
+        self.skip_blank();
+        let (line, column) = (self.line, self.column);
+        let (text, kind) = self.scan()?;
+        self.after_operand = matches!(kind, TokenType::NUMBER | TokenType::IDENT | TokenType::RIGHT_PAREN);
+        Ok(Token { text, kind, line, column })
+    }
+
+    fn scan(&mut self) -> Result<(String, TokenType), LexError> {
+        let mut text = String::new();
+        #[allow(unused_assignments)]
+        let mut kind = TokenType::BLANK;
+        match self.char {
+            '\0' => { text = String::from("EOF"); kind = TokenType::EOF; }
+            '\n' => { text = String::from("newline"); kind = TokenType::NEWLINE; }
+            '0'..='9' | '&' if self.radix_prefix().is_some() => {
+                let radix = self.radix_prefix().unwrap();
+                self.advance();
+                self.advance();
+                return Ok((self.number(radix)?, TokenType::NUMBER));
+            }
+            '0'..='9' => {
+                return Ok((self.number(10)?, TokenType::NUMBER));
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                while self.char.is_alphanumeric() || self.char == '_' {
+                    text.push(self.char);
+                    self.advance();
+                }
+                match text.to_lowercase().as_str() {
+                    "let"   => { kind = TokenType::LET; }
+                    "if"    => { kind = TokenType::IF; }
+                    "else"  => { kind = TokenType::ELSE; }
+                    "while" => { kind = TokenType::WHILE; }
+                    "for"   => { kind = TokenType::FOR; }
+                    "end"   => { kind = TokenType::END; }
+                    "then"  => { kind = TokenType::THEN; }
+                    "do"    => { kind = TokenType::DO; }
+                    "label" => { kind = TokenType::LABEL; }
+                    "goto"  => { kind = TokenType::GOTO; }
+                    "gosub" => { kind = TokenType::GOSUB; }
+                    "return"=> { kind = TokenType::RETURN; }
+                    "print" => { kind = TokenType::PRINT; }
+                    "input" => { kind = TokenType::INPUT; }
+                    "rem"   => { text = self.rest_of_line(); kind = TokenType::COMMENT; }
+                    _       => { text = self.case.apply(&text); kind = TokenType::IDENT; }
+                }
+                return Ok((text, kind));
+            }
+            '\'' => {
+                self.advance();
+                return Ok((self.rest_of_line(), TokenType::COMMENT));
+            }
+            '"' => {
+                return Ok((self.string()?, TokenType::STRING));
+            }
+            '+' => { text = String::from("+"); kind = TokenType::PLUS; }
+            '-' => { text = String::from("-"); kind = TokenType::MINUS; }
+            '*' => { text = String::from("*"); kind = TokenType::STAR; }
+            '/' => { text = String::from("/"); kind = TokenType::SLASH; }
+            '%' => { text = String::from("%"); kind = TokenType::PERCENT; }
+            '^' => { text = String::from("^"); kind = TokenType::CARET; }
+            '&' => { text = String::from("&"); kind = TokenType::AMPERSAND; }
+            '|' => { text = String::from("|"); kind = TokenType::PIPE; }
+            '=' => {
+                if self.peek() == '=' {
+                    self.advance();
+                    text = String::from("==");
+                    kind = TokenType::EQUAL_EQUAL;
+                } else {
+                    text = String::from("=");
+                    kind = TokenType::EQUAL;
+                }
+            }
+            '!' => {
+                if self.peek() == '=' {
+                    self.advance();
+                    text = String::from("!=");
+                    kind = TokenType::NOT_EQUAL;
+                } else {
+                    text = String::from("!");
+                    kind = TokenType::NOT;
+                }
+            }
+            '<' => {
+                if self.peek() == '=' {
+                    self.advance();
+                    text = String::from("<=");
+                    kind = TokenType::LESS_EQUAL;
+                } else {
+                    text = String::from("<");
+                    kind = TokenType::LESS;
+                }
+            }
+            '>' => {
+                if self.peek() == '=' {
+                    self.advance();
+                    text = String::from(">=");
+                    kind = TokenType::GREATER_EQUAL;
+                } else {
+                    text = String::from(">");
+                    kind = TokenType::GREATER;
+                }
+            }
+            '(' => { text = String::from("("); kind = TokenType::LEFT_PAREN; }
+            ')' => { text = String::from(")"); kind = TokenType::RIGHT_PAREN; }
+            ':' => { text = String::from(":"); kind = TokenType::COLON; }
+            ',' => { text = String::from(","); kind = TokenType::COMMA; }
+            _ => return Err(self.error(format!("Unknown character '{}'", self.char), self.line, self.column)),
+        }
+        // Multi-character tokens return above, leaving the lexer just past
+        // them; everything else still sits on its last character
+        self.advance();
+        Ok((text, kind))
+    }
+}
+
+impl Iterator for Lexer {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.get_token() {
+            Ok(token) if token.kind == TokenType::EOF => {
+                self.done = true;
+                None
+            }
+            Ok(token) => Some(Ok(token)),
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str, case: Case) -> Vec<(String, String)> {
+        Lexer::with_options(source.to_string(), case, WORD_BITS)
+            .map(|token| token.unwrap())
+            .map(|token| (token.kind.display(), token.text))
+            .collect()
+    }
+
+    fn error(source: &str) -> String {
+        Lexer::new(source.to_string()).find_map(|token| token.err()).unwrap().to_string()
+    }
+
+    fn kinds(source: &str) -> Vec<String> {
+        tokens(source, Case::Lower).into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    fn identifiers(source: &str, case: Case) -> Vec<String> {
+        tokens(source, case).into_iter().filter(|(kind, _)| kind == "IDENT").map(|(_, text)| text).collect()
+    }
+
+    #[test]
+    fn rem_and_apostrophe_comment_out_the_rest_of_the_line() {
+        let expected = [("LET", "let"), ("IDENT", "x"), ("EQUAL", "="), ("NUMBER", "1"), ("COMMENT", "set x"), ("NEWLINE", "newline"), ("COMMENT", "the rest"), ("NEWLINE", "newline")];
+        let expected: Vec<(String, String)> = expected.iter().map(|(kind, text)| (kind.to_string(), text.to_string())).collect();
+        assert_eq!(tokens("let x = 1 ' set x\nrem the rest\n", Case::Lower), expected);
+        assert_eq!(tokens("let remainder = 1", Case::Lower)[1], (String::from("IDENT"), String::from("remainder")));
+    }
+
+    #[test]
+    fn keywords_match_in_any_case() {
+        let expected = ["LET", "IDENT", "EQUAL", "NUMBER", "NEWLINE", "IF", "IDENT", "EQUAL_EQUAL", "NUMBER", "THEN", "NEWLINE", "END", "IF"];
+        assert_eq!(kinds("LET X = 1\nIF X == 1 THEN\nEND IF"), expected);
+        assert_eq!(kinds("let x = 1\nif x == 1 then\nend if"), expected);
+        assert_eq!(kinds("Let X = 1\niF x == 1 Then\nEnd iF"), expected);
+    }
+
+    #[test]
+    fn identifiers_follow_the_case_option() {
+        let source = "LET Count = 1\nIF count == 1 THEN\nCOUNT = 2\nEND IF";
+        assert_eq!(identifiers(source, Case::Lower), ["count", "count", "count"]);
+        assert_eq!(identifiers(source, Case::Upper), ["COUNT", "COUNT", "COUNT"]);
+        assert_eq!(identifiers(source, Case::Preserve), ["Count", "count", "COUNT"]);
+    }
+
+    #[test]
+    fn keyword_spelling_is_kept_in_the_token() {
+        assert_eq!(tokens("GoTo", Case::Lower), [(String::from("GOTO"), String::from("GoTo"))]);
+    }
+
+    #[test]
+    fn ampersand_after_an_operand_is_and() {
+        assert_eq!(kinds("a&h1"), ["IDENT", "AMPERSAND", "IDENT"]);
+        assert_eq!(kinds("mask&b1"), ["IDENT", "AMPERSAND", "IDENT"]);
+        assert_eq!(kinds("3&o7"), ["NUMBER", "AMPERSAND", "IDENT"]);
+        assert_eq!(tokens("x = a & &HFF", Case::Lower).last().unwrap(), &(String::from("NUMBER"), String::from("255")));
+        assert_eq!(tokens("x = &b101", Case::Lower).last().unwrap(), &(String::from("NUMBER"), String::from("5")));
+    }
+
+    fn string(source: &str) -> String {
+        let (kind, text) = tokens(source, Case::Lower).pop().unwrap();
+        assert_eq!(kind, "STRING");
+        text
+    }
+
+    #[test]
+    fn strings_take_doubled_quotes_and_escapes() {
+        assert_eq!(string("\"say \"\"hi\"\"\""), "say \"hi\"");
+        assert_eq!(string("\"a\\tb\\\\c\\\"d\\n\\0\""), "a\tb\\c\"d\n\0");
+        assert_eq!(string("\"\""), "");
+    }
+
+    #[test]
+    fn strings_end_on_their_line() {
+        assert_eq!(error("x\n  \"abc\ny = 1\""), "Unterminated string at line 2, column 3");
+    }
+
+    #[test]
+    fn strings_end_before_the_end_of_the_file() {
+        assert_eq!(error("x = \"abc"), "Unterminated string at line 1, column 5");
+    }
+
+    #[test]
+    fn unknown_escapes_are_reported() {
+        assert_eq!(error("\"a\\qb\""), "Unknown escape '\\q' in string at line 1, column 3");
+    }
+
+    #[test]
+    fn multi_byte_characters_are_kept_whole() {
+        assert_eq!(string("\"strïng ✓\""), "strïng ✓");
+        let tokens = tokens("let xö = 1 ' cömment\nlet y = \"日本\"", Case::Preserve);
+        assert_eq!(tokens[1], (String::from("IDENT"), String::from("xö")));
+        assert_eq!(tokens[4], (String::from("COMMENT"), String::from("cömment")));
+        assert_eq!(tokens[9], (String::from("STRING"), String::from("日本")));
+    }
+
+    #[test]
+    fn positions_count_characters_rather_than_bytes() {
+        assert_eq!(error("' ünïcödé\nlet x = \"é\" € 1"), "Unknown character '€' at line 2, column 13");
+    }
+
+    #[test]
+    fn tokens_record_where_they_start() {
+        let positions: Vec<(usize, usize)> = Lexer::new(String::from("let x = 10\n  print x"))
+            .map(|token| token.unwrap())
+            .map(|token| (token.line, token.column))
+            .collect();
+        assert_eq!(positions, [(1, 1), (1, 5), (1, 7), (1, 9), (1, 11), (2, 3), (2, 9)]);
+    }
+
+    #[test]
+    fn the_iterator_stops_after_the_first_error() {
+        let mut lexer = Lexer::new(String::from("x $ y"));
+        assert_eq!(lexer.next().unwrap().unwrap().text, "x");
+        let error = lexer.next().unwrap().unwrap_err();
+        assert_eq!((error.message.as_str(), error.line, error.column), ("Unknown character '$'", 1, 3));
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn the_iterator_ends_without_an_eof_token() {
+        let mut lexer = Lexer::new(String::from("x"));
+        assert_eq!(lexer.next(), Some(Ok(Token { text: String::from("x"), kind: TokenType::IDENT, line: 1, column: 1 })));
+        assert_eq!(lexer.next(), None);
+        assert_eq!(lexer.next(), None);
+    }
+}
@@ -0,0 +1,223 @@
+// A compiler from BASIC to assembly for a simple register machine. The
+// stages can be used on their own: `lexer` turns source into tokens,
+// `parser` turns tokens into a `Program` (see `ast`), `ir` lowers that to
+// basic blocks, and `codegen` turns those into assembly (or `x86` into
+// x86-64 assembly, `riscv` into RV32I assembly, `mos6502` into 6502
+// assembly, `wasm` into a WebAssembly module, or `bytecode` into bytecode
+// that `vm` runs). Each of those targets implements `backend::Backend`,
+// which is all a new one needs. `c` translates a `Program` to C instead.
+// `compile` runs them all, and `engine` runs scripts inside other programs.
+
+pub mod ast;
+pub mod backend;
+pub mod bytecode;
+pub mod c;
+pub mod codegen;
+pub mod dce;
+pub mod emulator;
+pub mod engine;
+pub mod diagnostic;
+pub mod fold;
+pub mod ir;
+pub mod lexer;
+pub mod loops;
+pub mod mos6502;
+pub mod parser;
+pub mod peephole;
+pub mod regalloc;
+pub mod riscv;
+pub mod vm;
+pub mod wasm;
+pub mod x86;
+
+pub use diagnostic::Diagnostic;
+
+use ast::Program;
+use lexer::{Case, Lexer, WORD_BITS};
+use parser::{Dialect, Parser};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    // The register machine the emulator runs
+    Isa,
+    // GNU as for Linux on x86-64
+    X86_64,
+    // GNU as for Linux on 32-bit RISC-V, which the emulator can also run
+    Rv32,
+    // ca65 for the 6502, whose words are 16 bits, so Options.word_bits
+    // must be mos6502::WORD_BITS
+    Mos6502,
+    // C99 source for any C compiler
+    C,
+    // A WebAssembly module, as WAT and as a binary
+    Wasm,
+    // Bytecode for the VM, as a listing and as a file
+    Bytecode,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    // Detected from the source when not given
+    pub dialect: Option<Dialect>,
+    pub case: Case,
+    // From 1 to 64
+    pub word_bits: u32,
+    // General purpose registers on the target, r0 and up
+    pub registers: usize,
+    // 0 compiles the program as written, 1 folds constants, simplifies
+    // expressions, removes dead code and runs the peephole pass, 2 also
+    // strength-reduces multiplications, optimises loops and keeps variables
+    // in registers
+    pub opt_level: u8,
+    pub target: Target,
+    // Functions the host provides, by name and how many arguments they
+    // take, and variables it gives the program. Only bytecode can have
+    // either, see `engine`.
+    pub functions: Vec<(String, usize)>,
+    pub globals: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { dialect: None, case: Case::Lower, word_bits: WORD_BITS, registers: 8, opt_level: 0, target: Target::Isa, functions: Vec::new(), globals: Vec::new() }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Output {
+    // The program for the target, which is C source for Target::C, WAT
+    // for Target::Wasm and a listing for Target::Bytecode
+    pub assembly: String,
+    // The encoded module, for targets with a binary format
+    pub binary: Option<Vec<u8>>,
+    // Instruction counts around the peephole pass, when it ran
+    pub peephole: Option<peephole::Report>,
+    // Unreachable code and unused variables, which don't stop compilation
+    pub warnings: Vec<Diagnostic>,
+    // Where each variable lives while the program runs, on the register
+    // machine
+    pub variables: Vec<(String, codegen::Location)>,
+    // The address of each instruction on the register machine that came
+    // from a statement, with the statement's line and column
+    pub source_map: Vec<(i32, usize, usize)>,
+}
+
+pub fn compile(source: &str, options: &Options) -> Result<Output, Vec<Diagnostic>> {
+    if options.target == Target::Isa && options.registers < regalloc::SCRATCH_REGISTERS {
+        let message = format!("The target needs at least {} registers", regalloc::SCRATCH_REGISTERS);
+        return Err(vec![Diagnostic::new(message, 0, 0)]);
+    }
+    if options.target == Target::Mos6502 && options.word_bits != mos6502::WORD_BITS {
+        return Err(vec![Diagnostic::new(format!("The 6502 has {}-bit words", mos6502::WORD_BITS), 0, 0)]);
+    }
+    if options.target != Target::Isa && options.target != Target::Mos6502 && options.word_bits != 32 {
+        return Err(vec![Diagnostic::new("Only the register machine can have words other than 32 bits".to_string(), 0, 0)]);
+    }
+    if options.target != Target::Bytecode && !(options.functions.is_empty() && options.globals.is_empty()) {
+        return Err(vec![Diagnostic::new("Only bytecode can call the host or use its variables".to_string(), 0, 0)]);
+    }
+    let program = parse(source, options)?;
+    // Warnings are about the program as written, before folding leaves out
+    // what an expression mentions
+    let written = ir::lower(&program)?;
+    let warnings = dce::warnings(&written);
+    let program = simplify(program, options);
+    let mut cfg = if options.opt_level >= 1 { ir::lower(&program)? } else { written };
+    // C keeps the shape of the program, so it is written from the statements
+    if options.target == Target::C {
+        return Ok(Output { assembly: c::generate(&program), binary: None, peephole: None, warnings, variables: Vec::new(), source_map: Vec::new() });
+    }
+    optimize(&mut cfg, options);
+    let assembly = match options.target {
+        Target::X86_64 => x86::generate(&cfg),
+        Target::Mos6502 => mos6502::generate(&cfg),
+        Target::Rv32 => {
+            let allocation = if options.opt_level >= 2 { regalloc::allocate(&cfg, riscv::REGISTERS) } else { regalloc::Allocation::new() };
+            riscv::generate(&cfg, &allocation)
+        }
+        Target::Wasm => {
+            let module = wasm::generate(&cfg);
+            return Ok(Output { assembly: module.wat(), binary: Some(module.encode()), peephole: None, warnings, variables: Vec::new(), source_map: Vec::new() });
+        }
+        Target::Bytecode => {
+            let chunk = bytecode::generate(&cfg).map_err(|diagnostic| vec![diagnostic])?;
+            let listing = chunk.disassemble()
+                .map_err(|error| vec![Diagnostic::new(format!("Generated bytecode does not decode: {}", error), 0, 0)])?;
+            return Ok(Output { assembly: listing, binary: Some(chunk.encode()), peephole: None, warnings, variables: Vec::new(), source_map: Vec::new() });
+        }
+        Target::C => unreachable!(),
+        Target::Isa => {
+            let allocation = if options.opt_level >= 2 { regalloc::allocate(&cfg, options.registers) } else { regalloc::Allocation::new() };
+            let mut lines = codegen::generate(&cfg, &allocation);
+            let peephole = if options.opt_level >= 1 { Some(peephole::optimize(&mut lines)) } else { None };
+            let assembly = codegen::assemble(&lines);
+            let variables = codegen::locations(&cfg, &allocation);
+            let source_map = codegen::source_map(&lines);
+            return Ok(Output { assembly, binary: None, peephole, warnings, variables, source_map });
+        }
+    };
+    Ok(Output { assembly, binary: None, peephole: None, warnings, variables: Vec::new(), source_map: Vec::new() })
+}
+
+// Compiles a program as far as the basic blocks that code is generated from
+pub fn lower(source: &str, options: &Options) -> Result<ir::Cfg, Vec<Diagnostic>> {
+    let mut cfg = ir::lower(&simplify(parse(source, options)?, options))?;
+    optimize(&mut cfg, options);
+    Ok(cfg)
+}
+
+fn simplify(mut program: Program, options: &Options) -> Program {
+    if options.opt_level >= 1 {
+        fold::fold(&mut program, options.word_bits, options.opt_level >= 2);
+    }
+    program
+}
+
+fn optimize(cfg: &mut ir::Cfg, options: &Options) {
+    if options.opt_level >= 1 {
+        dce::eliminate(cfg);
+    }
+    if options.opt_level >= 2 {
+        loops::optimize(cfg, options.word_bits);
+    }
+}
+
+// Lexes and parses a program, putting line-numbered listings in order first
+pub fn parse(source: &str, options: &Options) -> Result<Program, Vec<Diagnostic>> {
+    // Numbers are worked out in 64 bits while compiling
+    if !(1..=64).contains(&options.word_bits) {
+        return Err(vec![Diagnostic::new(format!("Words must have 1 to 64 bits, not {}", options.word_bits), 0, 0)]);
+    }
+    let dialect = options.dialect.unwrap_or_else(|| Dialect::detect(source));
+    let mut parser = match dialect {
+        Dialect::LineNumbered => {
+            let (ordered, line_map) = parser::order_lines(source)?;
+            let lexer = Lexer::with_options(ordered, options.case, options.word_bits);
+            Parser::with_line_map(lexer, dialect, line_map)
+        }
+        Dialect::Structured => {
+            let lexer = Lexer::with_options(source.to_string(), options.case, options.word_bits);
+            Parser::new(lexer, dialect)
+        }
+    };
+    parser.declare(&options.functions, &options.globals);
+    parser.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_bits_out_of_range_are_reported() {
+        for word_bits in [0, 65, 128] {
+            let options = Options { word_bits, ..Options::default() };
+            let diagnostics = compile("let x = 1", &options).unwrap_err();
+            assert_eq!(diagnostics[0].message, format!("Words must have 1 to 64 bits, not {}", word_bits));
+            assert!(lower("let x = 1", &options).is_err());
+        }
+        for word_bits in [1, 16, 64] {
+            assert!(compile("let x = 0", &Options { word_bits, ..Options::default() }).is_ok());
+        }
+    }
+}
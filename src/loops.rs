@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::BinaryOp;
+use crate::ir::{Block, BlockId, Cfg, Inst, Terminator, Value, Variable};
+
+// Loop optimisation. Loops are found in the control-flow graph, so a
+// `while` and a backward `goto` are treated the same.
+//
+// - A loop that tests its condition at the top and jumps back to it at the
+//   bottom tests it at the bottom as well, and jumps straight back into the
+//   body, so going round takes one jump instead of two.
+// - Anything worked out only from values the loop never changes is worked
+//   out once before the loop starts, into a variable of its own.
+// - An induction variable is one the loop only ever steps by a constant.
+//   Multiplying one by a constant becomes a variable that is stepped along
+//   with it, so the loop adds where it used to multiply.
+//
+// Loops that `gosub` are left alone, since the subroutine may change any
+// variable.
+pub fn optimize(cfg: &mut Cfg, word_bits: u32) {
+    rotate(cfg);
+    loop {
+        let hoisted = hoist(cfg);
+        if !hoisted && !reduce(cfg, word_bits) {
+            break;
+        }
+    }
+}
+
+struct Loop {
+    header: BlockId,
+    // In layout order, header included
+    blocks: Vec<BlockId>,
+}
+
+// Which blocks every path from the start of the program to a block goes
+// through, the block itself included
+fn dominators(cfg: &Cfg) -> HashMap<BlockId, HashSet<BlockId>> {
+    let predecessors = cfg.predecessors();
+    let entry = cfg.order[0];
+    let all: HashSet<BlockId> = cfg.order.iter().copied().collect();
+    let mut dominators: HashMap<BlockId, HashSet<BlockId>> = cfg.order.iter()
+        .map(|&block| (block, if block == entry { HashSet::from([entry]) } else { all.clone() }))
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &cfg.order[1..] {
+            let mut common: Option<HashSet<BlockId>> = None;
+            for predecessor in &predecessors[block] {
+                let theirs = &dominators[predecessor];
+                common = Some(match common {
+                    Some(common) => common.intersection(theirs).copied().collect(),
+                    None => theirs.clone(),
+                });
+            }
+            let mut new = common.unwrap_or_default();
+            new.insert(block);
+            if new != dominators[&block] {
+                dominators.insert(block, new);
+                changed = true;
+            }
+        }
+    }
+    dominators
+}
+
+// The loops that don't `gosub`, innermost first. A loop is everything that
+// can reach a jump back to a block that dominates it without going through
+// that block, its header.
+fn loops(cfg: &Cfg) -> Vec<Loop> {
+    let dominators = dominators(cfg);
+    let predecessors = cfg.predecessors();
+    let mut bodies: HashMap<BlockId, HashSet<BlockId>> = HashMap::new();
+    for &block in &cfg.order {
+        for header in cfg.blocks[block].terminator.successors() {
+            if dominators[&block].contains(&header) {
+                let body = bodies.entry(header).or_insert_with(|| HashSet::from([header]));
+                let mut work = vec![block];
+                while let Some(block) = work.pop() {
+                    if body.insert(block) {
+                        work.extend(&predecessors[block]);
+                    }
+                }
+            }
+        }
+    }
+    let mut loops: Vec<Loop> = bodies.into_iter()
+        .map(|(header, body)| Loop { header, blocks: cfg.order.iter().copied().filter(|block| body.contains(block)).collect() })
+        .filter(|found| !found.blocks.iter().any(|&block| matches!(cfg.blocks[block].terminator, Terminator::Call { .. })))
+        .collect();
+    loops.sort_by_key(|found| (found.blocks.len(), found.header));
+    loops
+}
+
+// The block to put code in that runs once before the loop. When the loop is
+// entered from more than a single jump, a new block is made for it.
+fn preheader(cfg: &mut Cfg, found: &Loop) -> BlockId {
+    let outside: Vec<BlockId> = cfg.predecessors()[found.header].iter()
+        .copied()
+        .filter(|block| !found.blocks.contains(block))
+        .collect();
+    if let [block] = outside[..] {
+        if cfg.blocks[block].terminator == Terminator::Jump(found.header) {
+            return block;
+        }
+    }
+    cfg.blocks.push(Block { insts: Vec::new(), terminator: Terminator::Jump(found.header), source: None });
+    let preheader = cfg.blocks.len() - 1;
+    for block in outside {
+        cfg.blocks[block].terminator.retarget(found.header, preheader);
+    }
+    let position = cfg.order.iter().position(|&block| block == found.header).unwrap();
+    cfg.order.insert(position, preheader);
+    preheader
+}
+
+fn new_variable(cfg: &mut Cfg, prefix: &str) -> Value {
+    // Variables the compiler makes up start with a dot, so they can never
+    // clash with the program's
+    let name = (cfg.variables.len()..)
+        .map(|number| format!(".{}{}", prefix, number))
+        .find(|name| cfg.variables.iter().all(|variable| variable.name != *name))
+        .unwrap();
+    cfg.variables.push(Variable { name: name.clone(), line: 0, column: 0, global: false });
+    Value::Var(name)
+}
+
+fn assigned(cfg: &Cfg, found: &Loop) -> HashSet<Value> {
+    found.blocks.iter()
+        .flat_map(|&block| cfg.blocks[block].insts.iter().filter_map(Inst::dest))
+        .cloned()
+        .collect()
+}
+
+fn rotate(cfg: &mut Cfg) {
+    let mut next_temp = cfg.temps();
+    for found in loops(cfg) {
+        let header = cfg.blocks[found.header].clone();
+        let Terminator::Branch { then, otherwise, .. } = header.terminator else { continue };
+        if found.blocks.contains(&then) == found.blocks.contains(&otherwise) {
+            continue;
+        }
+        for &latch in &found.blocks {
+            if latch == found.header || cfg.blocks[latch].terminator != Terminator::Jump(found.header) {
+                continue;
+            }
+            // The copy of the test gets temporaries of its own
+            let mut copy = header.clone();
+            copy.insts.retain(|inst| !matches!(inst, Inst::Comment(_)));
+            for index in 0..copy.insts.len() {
+                let Some(temp @ Value::Temp(_)) = copy.insts[index].dest().cloned() else { continue };
+                let fresh = Value::Temp(next_temp);
+                next_temp += 1;
+                if let Inst::Binary { dest, .. } | Inst::Copy { dest, .. } | Inst::Call { dest: Some(dest), .. } = &mut copy.insts[index] {
+                    *dest = fresh.clone();
+                }
+                for inst in &mut copy.insts[index + 1..] {
+                    inst.replace(&temp, &fresh);
+                }
+                copy.terminator.replace(&temp, &fresh);
+            }
+            let latch = &mut cfg.blocks[latch];
+            latch.insts.extend(copy.insts);
+            latch.terminator = copy.terminator;
+        }
+    }
+}
+
+// Whether an instruction gives the same temporary every time round the
+// loop. Variables hoisted out of an inner loop count too, so they can keep
+// going out to the outermost loop they are invariant in.
+fn is_invariant(inst: &Inst, assigned: &HashSet<Value>) -> bool {
+    let Inst::Binary { dest, op, left, right } = inst else { return false };
+    if !matches!(dest, Value::Temp(_)) && !matches!(dest, Value::Var(name) if name.starts_with(".inv")) {
+        return false;
+    }
+    let fixed = |value: &Value| match value {
+        Value::Const(_) => true,
+        Value::Var(_) => !assigned.contains(value),
+        Value::Temp(_) => false,
+    };
+    // Dividing by zero must not happen unless the program does it
+    let safe = !matches!(op, BinaryOp::Div | BinaryOp::Mod) || matches!(right, Value::Const(value) if *value != 0);
+    fixed(left) && fixed(right) && safe
+}
+
+// Hoists the invariant instructions out of the innermost loop that has any,
+// and says whether there was one
+fn hoist(cfg: &mut Cfg) -> bool {
+    for found in loops(cfg) {
+        let assigned = assigned(cfg, &found);
+        if !found.blocks.iter().any(|&block| cfg.blocks[block].insts.iter().any(|inst| is_invariant(inst, &assigned))) {
+            continue;
+        }
+        let preheader = preheader(cfg, &found);
+        for &block in &found.blocks {
+            // A hoisted temporary's use reads the new variable instead, which
+            // may make the instruction using it invariant in turn
+            let mut index = 0;
+            while index < cfg.blocks[block].insts.len() {
+                if !is_invariant(&cfg.blocks[block].insts[index], &assigned) {
+                    index += 1;
+                    continue;
+                }
+                let Inst::Binary { dest, op, left, right } = cfg.blocks[block].insts.remove(index) else { unreachable!() };
+                let variable = match (&dest, same_value(&cfg.blocks[preheader], op, &left, &right)) {
+                    (Value::Var(name), Some(variable)) => {
+                        cfg.variables.retain(|other| other.name != *name);
+                        for block in &mut cfg.blocks {
+                            for inst in &mut block.insts {
+                                inst.replace(&dest, &variable);
+                            }
+                            block.terminator.replace(&dest, &variable);
+                        }
+                        continue;
+                    }
+                    (Value::Var(_), None) => {
+                        cfg.blocks[preheader].insts.push(Inst::Binary { dest, op, left, right });
+                        continue;
+                    }
+                    (_, Some(variable)) => variable,
+                    (_, None) => {
+                        let variable = new_variable(cfg, "inv");
+                        cfg.blocks[preheader].insts.push(Inst::Binary { dest: variable.clone(), op, left, right });
+                        variable
+                    }
+                };
+                let block = &mut cfg.blocks[block];
+                for inst in &mut block.insts[index..] {
+                    inst.replace(&dest, &variable);
+                }
+                block.terminator.replace(&dest, &variable);
+            }
+        }
+        return true;
+    }
+    false
+}
+
+// A variable hoisted into the block earlier that still holds `left op right`
+// at the end of it
+fn same_value(block: &Block, op: BinaryOp, left: &Value, right: &Value) -> Option<Value> {
+    let mut found = None;
+    for inst in &block.insts {
+        match inst {
+            Inst::Binary { dest: dest @ Value::Var(name), op: o, left: l, right: r } if name.starts_with(".inv") && (*o, l, r) == (op, left, right) => {
+                found = Some(dest.clone());
+            }
+            _ => if let Some(dest) = inst.dest() {
+                if dest == left || dest == right || Some(dest) == found.as_ref() {
+                    found = None;
+                }
+            },
+        }
+    }
+    found
+}
+
+// The constant an instruction steps a variable by, if that is all it does
+fn step(inst: &Inst) -> Option<(&Value, i64)> {
+    match inst {
+        Inst::Binary { dest, op: BinaryOp::Add, left, right: Value::Const(step) } if left == dest => Some((dest, *step)),
+        Inst::Binary { dest, op: BinaryOp::Add, left: Value::Const(step), right } if right == dest => Some((dest, *step)),
+        Inst::Binary { dest, op: BinaryOp::Sub, left, right: Value::Const(step) } if left == dest => Some((dest, step.wrapping_neg())),
+        _ => None,
+    }
+}
+
+// Strength-reduces a multiplication of an induction variable in the
+// innermost loop that has one, and says whether there was one
+fn reduce(cfg: &mut Cfg, word_bits: u32) -> bool {
+    let wrap = |value: i64| {
+        let shift = 64 - word_bits;
+        (value << shift) >> shift
+    };
+    for found in loops(cfg) {
+        // Variables the loop assigns, and whether it only ever steps them
+        let mut induction: HashMap<&Value, bool> = HashMap::new();
+        for &block in &found.blocks {
+            for inst in &cfg.blocks[block].insts {
+                if let Some(dest @ Value::Var(_)) = inst.dest() {
+                    let stepped = step(inst).is_some();
+                    *induction.entry(dest).or_insert(stepped) &= stepped;
+                }
+            }
+        }
+        let is_induction = |value: &Value| induction.get(value) == Some(&true);
+        let candidate = found.blocks.iter().flat_map(|&block| &cfg.blocks[block].insts).find_map(|inst| match inst {
+            Inst::Binary { op: BinaryOp::Mul, left, right: Value::Const(factor), .. } if is_induction(left) => Some((inst.clone(), left.clone(), *factor)),
+            Inst::Binary { op: BinaryOp::Mul, left: Value::Const(factor), right, .. } if is_induction(right) => Some((inst.clone(), right.clone(), *factor)),
+            Inst::Binary { op: BinaryOp::Shl, left, right: Value::Const(shift), .. } if is_induction(left) && (0..word_bits as i64).contains(shift) => {
+                Some((inst.clone(), left.clone(), 1i64 << shift))
+            }
+            _ => None,
+        });
+        let Some((Inst::Binary { op, left, right, .. }, variable, factor)) = candidate else { continue };
+        let reduced = new_variable(cfg, "iv");
+        let preheader = preheader(cfg, &found);
+        cfg.blocks[preheader].insts.push(Inst::Binary { dest: reduced.clone(), op, left: left.clone(), right: right.clone() });
+        let update = |step: i64| Inst::Binary {
+            dest: reduced.clone(),
+            op: BinaryOp::Add,
+            left: reduced.clone(),
+            right: Value::Const(wrap(step.wrapping_mul(factor))),
+        };
+        for &block in &found.blocks {
+            let block = &mut cfg.blocks[block];
+            let mut insts = Vec::new();
+            // Temporaries that held the product, whose one use later in the
+            // block reads the new variable instead
+            let mut renamed = Vec::new();
+            for mut inst in std::mem::take(&mut block.insts) {
+                for temp in &renamed {
+                    inst.replace(temp, &reduced);
+                }
+                match inst {
+                    Inst::Binary { dest: dest @ Value::Temp(_), op: o, left: l, right: r } if (o, &l, &r) == (op, &left, &right) => renamed.push(dest),
+                    Inst::Binary { dest, op: o, left: l, right: r } if (o, &l, &r) == (op, &left, &right) => {
+                        insts.push(Inst::Copy { dest, src: reduced.clone() });
+                    }
+                    inst => {
+                        let stepped = step(&inst).filter(|(dest, _)| **dest == variable).map(|(_, step)| step);
+                        insts.push(inst);
+                        if let Some(step) = stepped {
+                            insts.push(update(step));
+                        }
+                    }
+                }
+            }
+            for temp in &renamed {
+                block.terminator.replace(temp, &reduced);
+            }
+            block.insts = insts;
+        }
+        return true;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator;
+    use crate::{compile, Options};
+
+    // The variables left once the program has run, and how many steps it
+    // took
+    fn run(source: &str, opt_level: u8) -> (Vec<(String, i64)>, u64) {
+        let options = Options { opt_level, ..Options::default() };
+        let output = compile(source, &options).unwrap();
+        let machine = emulator::run(&output.assembly, options.registers, options.word_bits, &mut std::iter::empty()).unwrap();
+        let values = output.variables.iter().map(|(name, location)| (name.clone(), machine.read(location))).collect();
+        (values, machine.steps)
+    }
+
+    // Runs the program at every level, checks the program's variables that
+    // are left end up as they do without optimising and returns the run at
+    // -O2. Variables the compiler made up start with a dot.
+    fn same_values(source: &str) -> (Vec<(String, i64)>, u64) {
+        let (expected, _) = run(source, 0);
+        let runs = [1, 2].map(|opt_level| (opt_level, run(source, opt_level)));
+        for (opt_level, (values, _)) in &runs {
+            for value in values.iter().filter(|(name, _)| !name.starts_with('.')) {
+                assert!(expected.contains(value), "{:?} at -O{}", value, opt_level);
+            }
+        }
+        let [_, (_, optimised)] = runs;
+        optimised
+    }
+
+    fn value(values: &[(String, i64)], name: &str) -> i64 {
+        values.iter().find(|(variable, _)| variable == name).unwrap().1
+    }
+
+    #[test]
+    fn loops_take_fewer_steps() {
+        let sources = [
+            // Rotated, with the product hoisted and the multiplication
+            // strength-reduced
+            "let n = 50\nlet k = 7\nlet i = 0\nlet s = 0\nwhile i < n do\ns = s + i * 3 + n * k\ni = i + 1\nend while\n",
+            "let i = 0\nlet s = 0\nlabel top\ns = s + i * 5\ni = i + 2\nif i < 40 then\ngoto top\nend if\n",
+        ];
+        for source in sources {
+            let (_, steps) = run(source, 1);
+            assert!(same_values(source).1 < steps, "{}", source);
+        }
+    }
+
+    #[test]
+    fn while_loops() {
+        let (values, _) = same_values("let i = 0\nlet s = 0\nwhile i < 10 do\ns = i * 4 + s\ni = i + 1\nend while\n");
+        assert_eq!((value(&values, "i"), value(&values, "s")), (10, 180));
+        let (values, _) = same_values("let i = 10\nlet s = 0\nwhile i < 10 do\ns = i + s\ni = i + 1\nend while\n");
+        assert_eq!((value(&values, "i"), value(&values, "s")), (10, 0));
+    }
+
+    #[test]
+    fn nested_loops() {
+        let (values, _) = same_values("let i = 0\nlet s = 0\nwhile i < 5 do\nlet j = 0\nwhile j < i do\ns = i * 10 + s\ns = j * 3 + s\nj = j + 1\nend while\ni = i + 1\nend while\n");
+        assert_eq!((value(&values, "i"), value(&values, "j"), value(&values, "s")), (5, 4, 330));
+    }
+
+    #[test]
+    fn loops_exited_with_goto() {
+        let (values, _) = same_values("let i = 0\nlet s = 0\nwhile i < 100 do\ns = i * 6 + s\nif i == 7 then\ngoto done\nend if\ni = i + 1\nend while\nlabel done\n");
+        assert_eq!((value(&values, "i"), value(&values, "s")), (7, 168));
+    }
+
+    #[test]
+    fn induction_variables_stepped_inside_an_if() {
+        let (values, _) = same_values("let i = 0\nlet n = 0\nlet s = 0\nwhile n < 20 do\nif n > 9 then\ni = i + 1\nend if\ns = i * 3 + s\nn = n + 1\nend while\n");
+        assert_eq!((value(&values, "i"), value(&values, "s")), (10, 165));
+    }
+}
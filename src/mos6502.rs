@@ -0,0 +1,557 @@
+use std::collections::HashMap;
+use crate::ast::BinaryOp;
+use crate::backend::{self, Backend};
+use crate::ir::{BlockId, Cfg, Value};
+
+// Words are 16 bits on the 6502, little-endian like the processor
+pub const WORD_BITS: u32 = 16;
+
+// Lowers the blocks of a program to 6502 assembly for ca65. The 6502 has
+// three 8-bit registers, so every value lives in memory and is worked on a
+// byte at a time through A: variables in DATA so they start at zero even
+// when the program is loaded over old memory, and temporaries in BSS,
+// sharing their two bytes with ones that are never live at the same time. A
+// `gosub` is a native jsr, which leaves room for around a hundred nested
+// calls on the processor's 256-byte stack. Multiplication, division,
+// shifts, print and input are runtime routines, with characters going
+// through CHROUT and CHRIN, which default to the C64 KERNAL's and can be
+// set with `ca65 -D`.
+pub fn generate(cfg: &Cfg) -> String {
+    let (slots, temps) = cfg.temp_slots();
+    let mut mos6502 = Mos6502 { lines: Vec::new(), labels: 0, slots };
+    mos6502.lines.push(HEADER.to_string());
+    mos6502.directive(".segment \"CODE\"");
+    // Where the stack started, so a `return` without a `gosub` is caught and
+    // `end` can leave from inside one
+    mos6502.label("start");
+    mos6502.code("cld".to_string());
+    mos6502.code("tsx".to_string());
+    mos6502.code("stx basic_stack".to_string());
+    backend::emit(cfg, &mut mos6502);
+    mos6502.label("basic_end");
+    mos6502.code("ldx basic_stack".to_string());
+    mos6502.code("txs".to_string());
+    mos6502.code("rts".to_string());
+    let mut assembly = mos6502.lines.join("\n");
+    assembly.push('\n');
+    assembly.push_str(RUNTIME);
+    if !cfg.variables.is_empty() {
+        assembly.push_str("\n    .segment \"DATA\"\n");
+        for variable in &cfg.variables {
+            assembly.push_str(&format!("{}: .word 0\n", symbol(&variable.name)));
+        }
+    }
+    if temps > 0 {
+        assembly.push_str("\n    .segment \"BSS\"\n");
+        for temp in 0..temps {
+            assembly.push_str(&format!("temp_{}: .res 2\n", temp));
+        }
+    }
+    assembly
+}
+
+// A variable's ca65 symbol, which is var_ and its name where the runtime's
+// are basic_. ca65 symbols can't hold a `.`, so the compiler's own
+// variables, which start with one, are compiler_ instead.
+fn symbol(name: &str) -> String {
+    match name.strip_prefix('.') {
+        Some(name) => format!("compiler_{}", name),
+        None => format!("var_{}", name),
+    }
+}
+
+fn block_label(block: BlockId) -> String {
+    format!("block_{}", block)
+}
+
+
+struct Mos6502 {
+    lines: Vec<String>,
+    // How many local labels have been made, which keeps them apart
+    labels: usize,
+    // Which temp_N each temporary is kept in
+    slots: HashMap<usize, usize>,
+}
+
+impl Mos6502 {
+    // The low (0) or high (1) byte of a value, as an operand
+    fn byte(&self, value: &Value, high: bool) -> String {
+        let offset = if high { "+1" } else { "" };
+        match value {
+            Value::Const(value) => format!("#${:02x}", (value >> if high { 8 } else { 0 }) & 0xff),
+            Value::Var(name) => format!("{}{}", symbol(name), offset),
+            Value::Temp(temp) => format!("temp_{}{}", self.slots[temp], offset),
+        }
+    }
+
+    fn code(&mut self, code: String) {
+        self.lines.push(format!("    {}", code));
+    }
+
+    fn directive(&mut self, directive: &str) {
+        self.lines.push(format!("    {}", directive));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(format!("{}:", label));
+    }
+
+    fn local(&mut self) -> String {
+        self.labels += 1;
+        format!("@l{}", self.labels)
+    }
+
+    // Copies a value into the word at `dest`
+    fn copy_to(&mut self, dest: &str, src: &Value) {
+        for high in [false, true] {
+            let offset = if high { "+1" } else { "" };
+            self.code(format!("lda {}", self.byte(src, high)));
+            self.code(format!("sta {}{}", dest, offset));
+        }
+    }
+
+    // Stores what a runtime routine left in basic_a
+    fn result(&mut self, dest: &Value) {
+        for high in [false, true] {
+            self.code(format!("lda basic_a{}", if high { "+1" } else { "" }));
+            self.code(format!("sta {}", self.byte(dest, high)));
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, left: &Value, right: &Value, dest: &Value) {
+        let (first, second) = match op {
+            BinaryOp::Add => ("clc", "adc"),
+            BinaryOp::Sub => ("sec", "sbc"),
+            BinaryOp::And => ("", "and"),
+            BinaryOp::Or  => ("", "ora"),
+            BinaryOp::Xor => ("", "eor"),
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::Shl => {
+                self.copy_to("basic_a", left);
+                self.copy_to("basic_b", right);
+                let routine = match op {
+                    BinaryOp::Mul => "basic_mul",
+                    BinaryOp::Div => "basic_div",
+                    BinaryOp::Mod => "basic_mod",
+                    _             => "basic_shl",
+                };
+                self.code(format!("jsr {}", routine));
+                self.result(dest);
+                return;
+            }
+            // A comparison leaves -1 (true) or 0 (false), like traditional
+            // BASIC. X is set before the test, which only uses A.
+            _ => {
+                self.code("ldx #$ff".to_string());
+                let holds = self.condition(op, left, right);
+                self.code("ldx #0".to_string());
+                self.label(&holds);
+                self.code(format!("stx {}", self.byte(dest, false)));
+                self.code(format!("stx {}", self.byte(dest, true)));
+                return;
+            }
+        };
+        if !first.is_empty() {
+            self.code(first.to_string());
+        }
+        // Carry goes from the low byte into the high one
+        for high in [false, true] {
+            self.code(format!("lda {}", self.byte(left, high)));
+            self.code(format!("{} {}", second, self.byte(right, high)));
+            self.code(format!("sta {}", self.byte(dest, high)));
+        }
+    }
+
+    // Compares two words and branches to a new local label when the
+    // comparison holds, returning the label
+    fn condition(&mut self, op: BinaryOp, left: &Value, right: &Value) -> String {
+        let label = self.local();
+        let (left, right, branch) = match op {
+            BinaryOp::Equal        => (left, right, "beq"),
+            BinaryOp::NotEqual     => (left, right, "bne"),
+            BinaryOp::Less         => (left, right, "bmi"),
+            BinaryOp::GreaterEqual => (left, right, "bpl"),
+            BinaryOp::Greater      => (right, left, "bmi"),
+            BinaryOp::LessEqual    => (right, left, "bpl"),
+            _ => panic!("Unknown condition {}", op.symbol()),
+        };
+        let tested = self.local();
+        match op {
+            // Z is set when both bytes are equal. A mismatch in the low
+            // byte gets to the end with Z still clear.
+            BinaryOp::Equal | BinaryOp::NotEqual => {
+                self.code(format!("lda {}", self.byte(left, false)));
+                self.code(format!("cmp {}", self.byte(right, false)));
+                self.code(format!("bne {}", tested));
+                self.code(format!("lda {}", self.byte(left, true)));
+                self.code(format!("cmp {}", self.byte(right, true)));
+            }
+            // Subtracting sets N xor V when left < right, and flipping N on
+            // overflow leaves that in N
+            _ => {
+                self.code(format!("lda {}", self.byte(left, false)));
+                self.code(format!("cmp {}", self.byte(right, false)));
+                self.code(format!("lda {}", self.byte(left, true)));
+                self.code(format!("sbc {}", self.byte(right, true)));
+                self.code(format!("bvc {}", tested));
+                self.code("eor #$80".to_string());
+            }
+        }
+        self.label(&tested);
+        self.code(format!("{} {}", branch, label));
+        label
+    }
+}
+
+impl Backend for Mos6502 {
+    fn label(&mut self, block: BlockId) {
+        self.label(&block_label(block));
+    }
+
+    fn copy(&mut self, dest: &Value, src: &Value) {
+        for high in [false, true] {
+            self.code(format!("lda {}", self.byte(src, high)));
+            self.code(format!("sta {}", self.byte(dest, high)));
+        }
+    }
+
+    fn arithmetic(&mut self, op: BinaryOp, dest: &Value, left: &Value, right: &Value) {
+        self.binary(op, left, right, dest);
+    }
+
+    fn print(&mut self, value: &Value) {
+        self.copy_to("basic_a", value);
+        self.code("jsr basic_print".to_string());
+    }
+
+    fn input(&mut self, dest: &Value) {
+        self.code("jsr basic_input".to_string());
+        self.result(dest);
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.lines.push(format!("    ; {}", text).trim_end().to_string());
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.code(format!("jmp {}", block_label(target)));
+    }
+
+    // Branches only reach 127 bytes, so they skip over a jmp instead of
+    // going to the block
+    fn branch(&mut self, op: BinaryOp, left: &Value, right: &Value, target: BlockId) {
+        let skip = self.condition(op.negated().unwrap(), left, right);
+        self.code(format!("jmp {}", block_label(target)));
+        self.label(&skip);
+    }
+
+    fn call(&mut self, target: BlockId, _after: BlockId) {
+        let room = self.local();
+        self.code("tsx".to_string());
+        self.code("cpx #STACK_LIMIT".to_string());
+        self.code(format!("bcs {}", room));
+        self.code("jmp basic_too_deep".to_string());
+        self.label(&room);
+        self.code(format!("jsr {}", block_label(target)));
+    }
+
+    fn ret(&mut self) {
+        let called = self.local();
+        self.code("tsx".to_string());
+        self.code("cpx basic_stack".to_string());
+        self.code(format!("bne {}", called));
+        self.code("jmp basic_return_without_call".to_string());
+        self.label(&called);
+        self.code("rts".to_string());
+    }
+
+    fn halt(&mut self) {
+        self.code("jmp basic_end".to_string());
+    }
+}
+
+// CHROUT writes the character in A and CHRIN reads one into A, giving 0 at
+// the end of the input. NEWLINE ends a printed number. A gosub needs
+// STACK_LIMIT bytes of stack left, for the runtime and interrupts.
+const HEADER: &str = "; Built with ca65, for example for the C64:
+;   cl65 -t c64 -C c64-asm.cfg -u __EXEHDR__ program.s -o program.prg
+
+.ifndef CHROUT
+CHROUT = $FFD2
+.endif
+.ifndef CHRIN
+CHRIN = $FFCF
+.endif
+.ifndef NEWLINE
+NEWLINE = $0D
+.endif
+STACK_LIMIT = $20
+";
+
+// The routines work on the words basic_a and basic_b and leave the result
+// in basic_a, with basic_r holding a remainder. basic_mul and basic_shl
+// keep the low 16 bits. basic_div and basic_mod divide the sizes and fix
+// the signs afterwards, so division rounds towards zero and the smallest
+// number divided by -1 wraps around, and dividing by zero stops the
+// program with an error, as on the emulator. basic_input reads a number
+// the same way the emulator does: anything up to the next digit or `-` is
+// skipped, the number ends at the first character that isn't a digit, and
+// at the end of the input it reads 0.
+const RUNTIME: &str = r#"
+basic_mul:
+    lda #0
+    sta basic_r
+    sta basic_r+1
+    ldx #16
+@loop:
+    lsr basic_b+1
+    ror basic_b
+    bcc @skip
+    clc
+    lda basic_r
+    adc basic_a
+    sta basic_r
+    lda basic_r+1
+    adc basic_a+1
+    sta basic_r+1
+@skip:
+    asl basic_a
+    rol basic_a+1
+    dex
+    bne @loop
+    lda basic_r
+    sta basic_a
+    lda basic_r+1
+    sta basic_a+1
+    rts
+
+; Shifts by the count the way the emulator does, which clears the word
+; once it reaches 16
+basic_shl:
+    lda basic_b
+    and #63
+    tax
+    beq @done
+@loop:
+    asl basic_a
+    rol basic_a+1
+    dex
+    bne @loop
+@done:
+    rts
+
+; Unsigned: basic_a becomes the quotient and basic_r the remainder
+basic_udiv:
+    lda #0
+    sta basic_r
+    sta basic_r+1
+    ldx #16
+@loop:
+    asl basic_a
+    rol basic_a+1
+    rol basic_r
+    rol basic_r+1
+    sec
+    lda basic_r
+    sbc basic_b
+    tay
+    lda basic_r+1
+    sbc basic_b+1
+    bcc @skip
+    sta basic_r+1
+    sty basic_r
+    inc basic_a
+@skip:
+    dex
+    bne @loop
+    rts
+
+; Divides the sizes, keeping the sign of the quotient in basic_sign and
+; that of the dividend in basic_sign+1
+basic_divide:
+    lda basic_b
+    ora basic_b+1
+    bne @divisor
+    jmp basic_division_by_zero
+@divisor:
+    lda basic_a+1
+    sta basic_sign+1
+    eor basic_b+1
+    sta basic_sign
+    lda basic_a+1
+    bpl @dividend
+    ldx #0
+    jsr basic_negate
+@dividend:
+    lda basic_b+1
+    bpl @positive
+    ldx #basic_b - basic_a
+    jsr basic_negate
+@positive:
+    jmp basic_udiv
+
+basic_div:
+    jsr basic_divide
+    lda basic_sign
+    bpl @done
+    ldx #0
+    jsr basic_negate
+@done:
+    rts
+
+basic_mod:
+    jsr basic_divide
+    lda basic_r
+    sta basic_a
+    lda basic_r+1
+    sta basic_a+1
+    lda basic_sign+1
+    bpl @done
+    ldx #0
+    jsr basic_negate
+@done:
+    rts
+
+; Negates the word X bytes after basic_a
+basic_negate:
+    sec
+    lda #0
+    sbc basic_a,x
+    sta basic_a,x
+    lda #0
+    sbc basic_a+1,x
+    sta basic_a+1,x
+    rts
+
+basic_print:
+    lda basic_a+1
+    bpl @positive
+    lda #'-'
+    jsr CHROUT
+    ldx #0
+    jsr basic_negate
+@positive:
+    lda #0
+    sta basic_digits
+@divide:
+    lda #10
+    sta basic_b
+    lda #0
+    sta basic_b+1
+    jsr basic_udiv
+    lda basic_r
+    pha
+    inc basic_digits
+    lda basic_a
+    ora basic_a+1
+    bne @divide
+@write:
+    pla
+    ora #'0'
+    jsr CHROUT
+    dec basic_digits
+    bne @write
+    lda #NEWLINE
+    jmp CHROUT
+
+basic_input:
+    lda #0
+    sta basic_a
+    sta basic_a+1
+@skip:
+    lda #0
+    sta basic_sign
+@sign:
+    jsr CHRIN
+    cmp #0
+    beq @done
+    cmp #'-'
+    bne @first
+    lda #$80
+    sta basic_sign
+    bne @sign
+@first:
+    sec
+    sbc #'0'
+    cmp #10
+    bcs @skip
+@digit:
+    pha
+    asl basic_a
+    rol basic_a+1
+    lda basic_a
+    sta basic_b
+    lda basic_a+1
+    sta basic_b+1
+    asl basic_a
+    rol basic_a+1
+    asl basic_a
+    rol basic_a+1
+    clc
+    lda basic_a
+    adc basic_b
+    sta basic_a
+    lda basic_a+1
+    adc basic_b+1
+    sta basic_a+1
+    pla
+    clc
+    adc basic_a
+    sta basic_a
+    lda basic_a+1
+    adc #0
+    sta basic_a+1
+    jsr CHRIN
+    sec
+    sbc #'0'
+    cmp #10
+    bcc @digit
+@done:
+    lda basic_sign
+    bpl @return
+    ldx #0
+    jsr basic_negate
+@return:
+    rts
+
+basic_division_by_zero:
+    ldy #basic_division_message - basic_messages
+    jmp basic_fail
+
+basic_return_without_call:
+    ldy #basic_return_message - basic_messages
+    jmp basic_fail
+
+basic_too_deep:
+    ldy #basic_deep_message - basic_messages
+    jmp basic_fail
+
+; Writes the message Y bytes into basic_messages and ends the program
+basic_fail:
+@loop:
+    lda basic_messages,y
+    beq @end
+    jsr CHROUT
+    iny
+    bne @loop
+@end:
+    lda #NEWLINE
+    jsr CHROUT
+    jmp basic_end
+
+    .segment "RODATA"
+basic_messages:
+basic_division_message:
+    .byte "Division by zero", 0
+basic_return_message:
+    .byte "Return without a call", 0
+basic_deep_message:
+    .byte "Too many nested gosubs", 0
+
+    .segment "BSS"
+basic_a: .res 2
+basic_b: .res 2
+basic_r: .res 2
+basic_sign: .res 2
+basic_digits: .res 1
+basic_stack: .res 1
+"#;
@@ -0,0 +1,595 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::ast::{BinaryOp, Expr, Program, Statement, StatementKind};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{Lexer, Token, TokenType};
+
+// Structured programs use `label`/`goto` by name. Line-numbered programs are
+// classic listings where every line starts with a number that doubles as its
+// label, e.g. `10 LET X = 1` / `20 GOTO 10`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dialect {
+    Structured,
+    LineNumbered,
+}
+
+impl Dialect {
+    pub fn detect(source: &str) -> Dialect {
+        match source.trim_start().chars().next() {
+            Some(c) if c.is_ascii_digit() => Dialect::LineNumbered,
+            _ => Dialect::Structured,
+        }
+    }
+}
+
+// Classic listings run in line number order, whatever order the lines were
+// typed in. A later line with the same number replaces an earlier one and a
+// bare line number deletes the line, just like typing it at the prompt.
+// Lines are kept as written, and the returned map gives the original line
+// of each line in the reordered source.
+pub fn order_lines(source: &str) -> Result<(String, Vec<usize>), Vec<Diagnostic>> {
+    let mut lines: BTreeMap<u32, (usize, &str)> = BTreeMap::new();
+    let mut diagnostics = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let text = line.trim_start();
+        if text.is_empty() {
+            continue;
+        }
+        let column = line.len() - text.len() + 1;
+        let digits: String = text.chars().take_while(|c| c.is_ascii_digit()).collect();
+        if digits.is_empty() {
+            diagnostics.push(Diagnostic::new("Line has no line number".to_string(), index + 1, column));
+            continue;
+        }
+        match digits.parse::<u32>() {
+            Ok(number) if text[digits.len()..].trim().is_empty() => { lines.remove(&number); }
+            Ok(number) => { lines.insert(number, (index + 1, line)); }
+            Err(_) => diagnostics.push(Diagnostic::new(format!("Line number {} is too large", digits), index + 1, column)),
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    let line_map = lines.values().map(|(line, _)| *line).collect();
+    let ordered = lines.values().map(|(_, text)| *text).collect::<Vec<&str>>().join("\n");
+    Ok((ordered, line_map))
+}
+
+pub struct Parser {
+    lexer: Lexer,
+    dialect: Dialect,
+    current: Token,
+    peek: Token,
+    symbols: HashSet<String>,
+    labels: HashSet<String>,
+    // Host functions and how many arguments each takes
+    functions: HashMap<String, usize>,
+    globals: Vec<String>,
+    line_map: Vec<usize>,
+    lex_failed: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Parser {
+    pub fn new(lexer: Lexer, dialect: Dialect) -> Parser {
+        Parser::with_line_map(lexer, dialect, Vec::new())
+    }
+
+    // Used when the lexer reads reordered source, so diagnostics and
+    // statements still point at the lines as written (see order_lines)
+    pub fn with_line_map(lexer: Lexer, dialect: Dialect, line_map: Vec<usize>) -> Parser {
+        let mut parser = Parser {
+            lexer,
+            dialect,
+            current: Token::new(String::from(""), TokenType::BLANK),
+            peek: Token::new(String::from(""), TokenType::BLANK),
+            symbols: HashSet::new(),
+            labels: HashSet::new(),
+            functions: HashMap::new(),
+            globals: Vec::new(),
+            line_map,
+            lex_failed: false,
+            diagnostics: Vec::new(),
+        };
+        parser.next();
+        parser.next();
+        parser
+    }
+
+    // Lets the program call the host's functions, given by name and how
+    // many arguments they take, and use the host's variables without
+    // declaring them
+    pub fn declare(&mut self, functions: &[(String, usize)], globals: &[String]) {
+        self.functions.extend(functions.iter().cloned());
+        self.symbols.extend(globals.iter().cloned());
+        self.globals.extend(globals.iter().cloned());
+    }
+
+    pub fn parse(mut self) -> Result<Program, Vec<Diagnostic>> {
+        let statements = self.block(None).unwrap_or_default();
+        if self.diagnostics.is_empty() {
+            Ok(Program { statements, globals: self.globals })
+        } else {
+            self.diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+            Err(self.diagnostics)
+        }
+    }
+
+    // A lexing error is reported once and then reads as the end of the file.
+    // Anything that goes wrong at that end of file is a knock-on effect and
+    // is not reported.
+    fn next(&mut self) {
+        let token = if self.lex_failed {
+            Token::new(String::from("EOF"), TokenType::EOF)
+        } else {
+            match self.lexer.get_token() {
+                Ok(token) => token,
+                Err(error) => {
+                    self.lex_failed = true;
+                    let mut diagnostic = Diagnostic::from(error);
+                    diagnostic.line = self.source_line(diagnostic.line);
+                    self.diagnostics.push(diagnostic);
+                    Token::new(String::from("EOF"), TokenType::EOF)
+                }
+            }
+        };
+        self.current = std::mem::replace(&mut self.peek, token);
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if !(self.lex_failed && self.check_token(TokenType::EOF)) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn source_line(&self, line: usize) -> usize {
+        self.line_map.get(line.wrapping_sub(1)).copied().unwrap_or(line)
+    }
+
+    fn position(&self) -> (usize, usize) {
+        (self.source_line(self.current.line), self.current.column)
+    }
+
+    fn error(&self, message: String) -> Diagnostic {
+        let (line, column) = self.position();
+        Diagnostic::new(message, line, column)
+    }
+
+    fn check_token(&self, kind: TokenType) -> bool {
+        self.current.kind == kind
+    }
+
+    fn _match(&mut self, kind: TokenType) -> Result<(), Diagnostic> {
+        if self.current.kind == kind {
+            self.next();
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected {}, got '{}'", kind.display(), self.current.text)))
+        }
+    }
+
+    // Skips the rest of a statement that failed to parse
+    fn recover(&mut self) {
+        while !matches!(self.current.kind, TokenType::NEWLINE | TokenType::COLON | TokenType::EOF) {
+            self.next();
+        }
+        if !self.check_token(TokenType::EOF) {
+            self.next();
+        }
+    }
+
+    // Parses statements up to the end of the file, or up to and including
+    // `end if`/`end while` when `closing` is IF or WHILE. Returns None if the
+    // file ends before the block is closed.
+    fn block(&mut self, closing: Option<TokenType>) -> Option<Vec<Statement>> {
+        let mut statements = Vec::new();
+        loop {
+            if self.check_token(TokenType::EOF) {
+                return if closing.is_some() { None } else { Some(statements) };
+            }
+            if self.check_token(TokenType::END) && matches!(self.peek.kind, TokenType::IF | TokenType::WHILE) {
+                if closing.as_ref() == Some(&self.peek.kind) {
+                    self.next();
+                    self.next();
+                    return Some(statements);
+                }
+                let block = self.peek.text.to_lowercase();
+                let error = self.error(format!("end {} without {}", block, block));
+                self.report(error);
+                self.recover();
+                continue;
+            }
+            if let Err(diagnostic) = self.statement(&mut statements) {
+                self.report(diagnostic);
+                self.recover();
+            }
+        }
+    }
+
+    fn statement(&mut self, statements: &mut Vec<Statement>) -> Result<(), Diagnostic> {
+        let (line, column) = self.position();
+        let kind = match self.current.kind {
+            TokenType::NEWLINE | TokenType::COLON => {
+                self.next();
+                return Ok(());
+            }
+            // A leading line number labels the statement that follows it
+            TokenType::NUMBER if self.dialect == Dialect::LineNumbered => {
+                let number = self.line_number()?;
+                self.labels.insert(number.clone());
+                statements.push(Statement { kind: StatementKind::Label(number), line, column });
+                return Ok(());
+            }
+            TokenType::COMMENT => {
+                let text = self.current.text.clone();
+                self.next();
+                StatementKind::Comment(text)
+            }
+            // Classic listings say LET for any assignment, so there it only
+            // declares a variable the first time
+            TokenType::LET => {
+                self._match(TokenType::LET)?;
+                let name = self.current.text.clone();
+                self._match(TokenType::IDENT)?;
+                let exists = self.symbols.contains(&name);
+                if exists && self.dialect != Dialect::LineNumbered {
+                    return Err(Diagnostic::new(format!("Variable {} already exists", name), line, column));
+                }
+                self._match(TokenType::EQUAL)?;
+                let value = self.expression()?;
+                if exists {
+                    StatementKind::Assign { name, value }
+                } else {
+                    self.symbols.insert(name.clone());
+                    StatementKind::Let { name, value }
+                }
+            }
+            TokenType::IDENT if self.peek.kind == TokenType::LEFT_PAREN => {
+                let (name, arguments) = self.call()?;
+                StatementKind::Call { name, arguments }
+            }
+            // and a variable needs no LET at all, where the first assignment
+            // declares it
+            TokenType::IDENT => {
+                let name = self.current.text.clone();
+                let exists = self.symbols.contains(&name);
+                if !exists && self.dialect != Dialect::LineNumbered {
+                    return Err(self.error(format!("Variable {} does not exist", name)));
+                }
+                self._match(TokenType::IDENT)?;
+                self._match(TokenType::EQUAL)?;
+                let value = self.expression()?;
+                if exists {
+                    StatementKind::Assign { name, value }
+                } else {
+                    self.symbols.insert(name.clone());
+                    StatementKind::Let { name, value }
+                }
+            }
+            TokenType::IF => {
+                self._match(TokenType::IF)?;
+                let condition = self.expression()?;
+                self._match(TokenType::THEN)?;
+                if self.dialect == Dialect::LineNumbered && !matches!(self.current.kind, TokenType::NEWLINE | TokenType::COMMENT | TokenType::EOF) {
+                    let body = self.rest_of_line()?;
+                    statements.push(Statement { kind: StatementKind::If { condition, body }, line, column });
+                    return Ok(());
+                }
+                let body = self.block(Some(TokenType::IF))
+                    .ok_or_else(|| Diagnostic::new("Missing end if".to_string(), line, column))?;
+                StatementKind::If { condition, body }
+            }
+            TokenType::WHILE => {
+                self._match(TokenType::WHILE)?;
+                let condition = self.expression()?;
+                self._match(TokenType::DO)?;
+                let body = self.block(Some(TokenType::WHILE))
+                    .ok_or_else(|| Diagnostic::new("Missing end while".to_string(), line, column))?;
+                StatementKind::While { condition, body }
+            }
+            // `end if`/`end while` are handled by block, a bare `end` stops the program
+            TokenType::END => {
+                self._match(TokenType::END)?;
+                StatementKind::End
+            }
+            TokenType::LABEL => {
+                self._match(TokenType::LABEL)?;
+                let name = self.current.text.clone();
+                self._match(TokenType::IDENT)?;
+                if !self.labels.insert(name.clone()) {
+                    return Err(Diagnostic::new(format!("Label {} already exists", name), line, column));
+                }
+                StatementKind::Label(name)
+            }
+            TokenType::GOTO => {
+                self._match(TokenType::GOTO)?;
+                StatementKind::Goto(self.jump_target()?)
+            }
+            TokenType::GOSUB => {
+                self._match(TokenType::GOSUB)?;
+                StatementKind::Gosub(self.jump_target()?)
+            }
+            TokenType::RETURN => {
+                self._match(TokenType::RETURN)?;
+                StatementKind::Return
+            }
+            TokenType::PRINT => {
+                self._match(TokenType::PRINT)?;
+                if self.check_token(TokenType::STRING) {
+                    return Err(self.error("Only numbers can be printed, strings are not supported yet".to_string()));
+                }
+                StatementKind::Print(self.expression()?)
+            }
+            TokenType::INPUT => {
+                self._match(TokenType::INPUT)?;
+                let name = self.current.text.clone();
+                if self.check_token(TokenType::IDENT) && !self.symbols.contains(&name) {
+                    return Err(self.error(format!("Variable {} does not exist", name)));
+                }
+                self._match(TokenType::IDENT)?;
+                StatementKind::Input(name)
+            }
+            TokenType::FOR => return Err(self.error("for loops are not supported yet".to_string())),
+            _ => return Err(self.error(format!("Unexpected token: {}", self.current.text))),
+        };
+        statements.push(Statement { kind, line, column });
+        self.end_statement(statements)
+    }
+
+    // Every statement ends at a newline, a `:` or the end of the file, and
+    // may be followed by a comment on the same line
+    fn end_statement(&mut self, statements: &mut Vec<Statement>) -> Result<(), Diagnostic> {
+        if self.check_token(TokenType::COMMENT) {
+            let (line, column) = self.position();
+            statements.push(Statement { kind: StatementKind::Comment(self.current.text.clone()), line, column });
+            self.next();
+        }
+        match self.current.kind {
+            TokenType::NEWLINE | TokenType::COLON => {
+                self.next();
+                Ok(())
+            }
+            TokenType::EOF => Ok(()),
+            _ => Err(self.error(format!("Expected end of statement, got '{}'", self.current.text))),
+        }
+    }
+
+    // The body of a one-line `IF ... THEN` in a classic listing, which is the
+    // rest of the line. A line number straight after THEN is a `goto`.
+    fn rest_of_line(&mut self) -> Result<Vec<Statement>, Diagnostic> {
+        let mut body = Vec::new();
+        let line = self.current.line;
+        if self.check_token(TokenType::NUMBER) {
+            let (line, column) = self.position();
+            let target = self.line_number()?;
+            body.push(Statement { kind: StatementKind::Goto(target), line, column });
+            self.end_statement(&mut body)?;
+        }
+        while self.current.line == line && !self.check_token(TokenType::EOF) {
+            self.statement(&mut body)?;
+        }
+        Ok(body)
+    }
+
+    fn line_number(&mut self) -> Result<String, Diagnostic> {
+        let number = self.current.text.parse::<u32>()
+            .map_err(|_| self.error(format!("Line number {} is too large", self.current.text)))?;
+        self._match(TokenType::NUMBER)?;
+        Ok(number.to_string())
+    }
+
+    // Targets of `goto`/`gosub` are names in structured programs and line
+    // numbers in classic ones
+    fn jump_target(&mut self) -> Result<String, Diagnostic> {
+        if self.check_token(TokenType::NUMBER) {
+            self.line_number()
+        } else {
+            let name = self.current.text.clone();
+            self._match(TokenType::IDENT)?;
+            Ok(name)
+        }
+    }
+
+    fn operator(&self) -> Option<BinaryOp> {
+        match self.current.kind {
+            TokenType::PLUS      => Some(BinaryOp::Add),
+            TokenType::MINUS     => Some(BinaryOp::Sub),
+            TokenType::STAR      => Some(BinaryOp::Mul),
+            TokenType::SLASH     => Some(BinaryOp::Div),
+            TokenType::PERCENT   => Some(BinaryOp::Mod),
+            TokenType::CARET     => Some(BinaryOp::Xor),
+            TokenType::AMPERSAND => Some(BinaryOp::And),
+            TokenType::PIPE      => Some(BinaryOp::Or),
+            _ => None,
+        }
+    }
+
+    fn relation(&self) -> Option<BinaryOp> {
+        match self.current.kind {
+            // Classic listings compare with a single `=`
+            TokenType::EQUAL if self.dialect == Dialect::LineNumbered => Some(BinaryOp::Equal),
+            TokenType::EQUAL_EQUAL   => Some(BinaryOp::Equal),
+            TokenType::NOT_EQUAL     => Some(BinaryOp::NotEqual),
+            TokenType::LESS          => Some(BinaryOp::Less),
+            TokenType::GREATER       => Some(BinaryOp::Greater),
+            TokenType::LESS_EQUAL    => Some(BinaryOp::LessEqual),
+            TokenType::GREATER_EQUAL => Some(BinaryOp::GreaterEqual),
+            _ => None,
+        }
+    }
+
+    // A comparison of two arithmetic expressions, or just one of them
+    fn expression(&mut self) -> Result<Expr, Diagnostic> {
+        let left = self.arithmetic()?;
+        match self.relation() {
+            Some(op) => {
+                self.next();
+                let right = self.arithmetic()?;
+                Ok(Expr::Binary(op, Box::new(left), Box::new(right)))
+            }
+            None => Ok(left),
+        }
+    }
+
+    // Operators have no precedence and are applied left to right
+    fn arithmetic(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.atom()?;
+        while let Some(op) = self.operator() {
+            self.next();
+            let right = self.atom()?;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn atom(&mut self) -> Result<Expr, Diagnostic> {
+        if self.check_token(TokenType::NUMBER) {
+            let value = self.current.text.parse::<i64>().unwrap();
+            self._match(TokenType::NUMBER)?;
+            Ok(Expr::Number(value))
+        } else if self.check_token(TokenType::IDENT) && self.peek.kind == TokenType::LEFT_PAREN {
+            let (name, arguments) = self.call()?;
+            Ok(Expr::Call(name, arguments))
+        } else if self.check_token(TokenType::IDENT) {
+            let name = self.current.text.clone();
+            if !self.symbols.contains(&name) {
+                return Err(self.error(format!("Undefined variable {}", name)));
+            }
+            self._match(TokenType::IDENT)?;
+            Ok(Expr::Variable(name))
+        } else {
+            Err(self.error(format!("Expected number or identifier for expression, got '{}'", self.current.text)))
+        }
+    }
+    // A host function and its arguments, e.g. `clamp(x, 0, 10)`
+    fn call(&mut self) -> Result<(String, Vec<Expr>), Diagnostic> {
+        let (line, column) = self.position();
+        let name = self.current.text.clone();
+        let Some(&count) = self.functions.get(&name) else {
+            return Err(self.error(format!("Function {} does not exist", name)));
+        };
+        self._match(TokenType::IDENT)?;
+        self._match(TokenType::LEFT_PAREN)?;
+        let mut arguments = Vec::new();
+        if !self.check_token(TokenType::RIGHT_PAREN) {
+            arguments.push(self.expression()?);
+            while self.check_token(TokenType::COMMA) {
+                self.next();
+                arguments.push(self.expression()?);
+            }
+        }
+        self._match(TokenType::RIGHT_PAREN)?;
+        if arguments.len() != count {
+            let plural = if count == 1 { "" } else { "s" };
+            return Err(Diagnostic::new(format!("Function {} takes {} argument{}", name, count, plural), line, column));
+        }
+        Ok((name, arguments))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Case, WORD_BITS};
+
+    fn parse(source: &str, case: Case) -> Result<Program, Vec<Diagnostic>> {
+        Parser::new(Lexer::with_options(source.to_string(), case, WORD_BITS), Dialect::detect(source)).parse()
+    }
+
+    // The names a program declares, assigns and tests, in order
+    fn names(program: &Program) -> Vec<String> {
+        fn walk(statements: &[Statement], names: &mut Vec<String>) {
+            for statement in statements {
+                match &statement.kind {
+                    StatementKind::Let { name, .. } | StatementKind::Assign { name, .. } => names.push(name.clone()),
+                    StatementKind::If { condition, body } => {
+                        if let Expr::Binary(_, left, _) = condition {
+                            if let Expr::Variable(name) = &**left {
+                                names.push(name.clone());
+                            }
+                        }
+                        walk(body, names);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut names = Vec::new();
+        walk(&program.statements, &mut names);
+        names
+    }
+
+    fn ifs(program: &Program) -> Vec<&Vec<Statement>> {
+        program.statements.iter().filter_map(|statement| match &statement.kind {
+            StatementKind::If { body, .. } => Some(body),
+            _ => None,
+        }).collect()
+    }
+
+    const LISTING: &str = "LET X = 1\nIF X == 1 THEN\n  X = 2\nEND IF\n";
+    const MIXED: &str = "Let Total = 1\nIf total == 1 Then\n  TOTAL = 2\nEnd If\n";
+
+    #[test]
+    fn uppercase_listings_parse() {
+        assert_eq!(names(&parse(LISTING, Case::Lower).unwrap()), ["x", "x", "x"]);
+        assert_eq!(names(&parse(LISTING, Case::Upper).unwrap()), ["X", "X", "X"]);
+        assert_eq!(names(&parse(LISTING, Case::Preserve).unwrap()), ["X", "X", "X"]);
+    }
+
+    #[test]
+    fn mixed_case_names_are_one_variable_unless_case_is_preserved() {
+        assert_eq!(names(&parse(MIXED, Case::Lower).unwrap()), ["total", "total", "total"]);
+        assert_eq!(names(&parse(MIXED, Case::Upper).unwrap()), ["TOTAL", "TOTAL", "TOTAL"]);
+        let diagnostics = parse(MIXED, Case::Preserve).unwrap_err();
+        assert_eq!(diagnostics[0].message, "Undefined variable total");
+    }
+
+    #[test]
+    fn line_numbered_listings_parse_in_upper_case() {
+        let program = parse("10 LET X = 1\n20 IF X == 1 THEN\n30 X = 2\n40 END IF\n50 GOTO 10\n", Case::Lower).unwrap();
+        assert_eq!(names(&program), ["x", "x", "x"]);
+    }
+
+    #[test]
+    fn classic_listings_have_one_line_ifs() {
+        let program = parse("10 LET X = 1\n20 IF X = 1 THEN 100\n30 IF X THEN GOTO 100\n40 IF X THEN X = 2 : X = 3\n100 END\n", Case::Lower).unwrap();
+        let ifs = ifs(&program);
+        assert_eq!(ifs.len(), 3);
+        assert_eq!(ifs[0][0].kind, StatementKind::Goto(String::from("100")));
+        assert_eq!(ifs[1][0].kind, StatementKind::Goto(String::from("100")));
+        assert_eq!(ifs[2].len(), 2);
+        assert!(matches!(program.statements[3].kind, StatementKind::If { condition: Expr::Binary(BinaryOp::Equal, ..), .. }));
+    }
+
+    #[test]
+    fn one_line_ifs_run_to_the_end_of_the_line() {
+        let program = parse("10 LET X = 1\n20 IF X THEN X = 2 : Y = 3\n30 Z = 4\n", Case::Lower).unwrap();
+        assert_eq!(names(&program), ["x", "x", "y", "z"]);
+        assert_eq!(ifs(&program)[0].len(), 2);
+    }
+
+    #[test]
+    fn classic_listings_repeat_let_and_can_leave_it_out() {
+        let program = parse("10 LET I = 1\n20 LET I = I + 1\n30 J = I\n40 J = J * 2\n", Case::Lower).unwrap();
+        let assignments: Vec<(bool, &str)> = program.statements.iter().filter_map(|statement| match &statement.kind {
+            StatementKind::Let { name, .. } => Some((true, name.as_str())),
+            StatementKind::Assign { name, .. } => Some((false, name.as_str())),
+            _ => None,
+        }).collect();
+        assert_eq!(assignments, [(true, "i"), (false, "i"), (true, "j"), (false, "j")]);
+        // Structured programs still declare each variable once
+        assert_eq!(parse("let i = 1\nlet i = 2\n", Case::Lower).unwrap_err()[0].message, "Variable i already exists");
+        assert_eq!(parse("j = 1\n", Case::Lower).unwrap_err()[0].message, "Variable j does not exist");
+    }
+
+    #[test]
+    fn printing_a_string_is_reported() {
+        let diagnostics = parse("10 PRINT \"HI\"\n", Case::Lower).unwrap_err();
+        assert_eq!(diagnostics[0].message, "Only numbers can be printed, strings are not supported yet");
+    }
+
+    #[test]
+    fn statements_on_one_line_need_a_colon() {
+        let diagnostics = parse("let x = 1 y = 2\n", Case::Lower).unwrap_err();
+        assert_eq!(diagnostics[0].message, "Expected end of statement, got 'y'");
+    }
+}
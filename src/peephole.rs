@@ -0,0 +1,233 @@
+use crate::codegen::{Instruction, Line};
+
+// How many instructions there were before and after the peephole pass
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Report {
+    pub before: usize,
+    pub after: usize,
+}
+
+// Cleans up the code generator's output one small window at a time, until
+// nothing more changes:
+//
+// - `set bp N bp` when bp already holds N
+// - moves from a register to itself
+// - moving a value straight back where it was just moved from, such as
+//   loading a variable that was just stored
+// - `addi r0 0 r2` ... `addi r2 0 r1` around a load that leaves r1 alone
+// - jumps to the very next instruction
+pub fn optimize(lines: &mut Vec<Line>) -> Report {
+    let before = count(lines);
+    loop {
+        let mut changed = remove_self_moves(lines);
+        changed |= remove_bp_reloads(lines);
+        changed |= forward_stores(lines);
+        changed |= fold_shuffles(lines);
+        changed |= remove_jumps_to_next(lines);
+        if !changed {
+            break;
+        }
+    }
+    Report { before, after: count(lines) }
+}
+
+pub fn count(lines: &[Line]) -> usize {
+    lines.iter().filter(|line| matches!(line, Line::Instruction(_))).count()
+}
+
+fn is_jump(instruction: &Instruction) -> bool {
+    instruction.target().is_some() || matches!(instruction.op.as_str(), "jmp" | "jeq" | "jne" | "jlt" | "jgt" | "jle" | "jge" | "call" | "ret")
+}
+
+// The register an instruction writes, which is always its last operand
+fn writes(instruction: &Instruction) -> Option<&str> {
+    if is_jump(instruction) { None } else { Some(&instruction.operands[2]) }
+}
+
+fn reads(instruction: &Instruction, register: &str) -> bool {
+    match instruction.op.as_str() {
+        "set" => false,
+        "addi" => instruction.operands[0] == register,
+        _ => instruction.operands[0] == register || instruction.operands[1] == register,
+    }
+}
+
+fn is_move(instruction: &Instruction, from: &str, to: &str) -> bool {
+    instruction.op == "addi" && instruction.operands == [from.to_string(), String::from("0"), to.to_string()]
+}
+
+fn remove_self_moves(lines: &mut Vec<Line>) -> bool {
+    let before = lines.len();
+    lines.retain(|line| !matches!(line, Line::Instruction(i) if i.op == "addi" && i.operands[1] == "0" && i.operands[0] == i.operands[2]));
+    lines.len() != before
+}
+
+// bp is only known between labels, since a jump may arrive with anything in
+// it, and a subroutine may leave anything in it
+fn remove_bp_reloads(lines: &mut Vec<Line>) -> bool {
+    let mut bp: Option<String> = None;
+    let before = lines.len();
+    lines.retain(|line| match line {
+        Line::Label(_) => {
+            bp = None;
+            true
+        }
+        Line::Comment(_) => true,
+        Line::Instruction(instruction) => {
+            if instruction.op == "set" && instruction.operands[0] == "bp" && instruction.operands[2] == "bp" {
+                if bp.as_deref() == Some(instruction.operands[1].as_str()) {
+                    return false;
+                }
+                bp = Some(instruction.operands[1].clone());
+            } else if instruction.op == "call" || writes(instruction) == Some("bp") {
+                bp = None;
+            }
+            true
+        }
+    });
+    lines.len() != before
+}
+
+// Index of the next instruction after `index`, looking past comments only
+fn next_instruction(lines: &[Line], index: usize) -> Option<usize> {
+    let mut next = index + 1;
+    while let Some(Line::Comment(_)) = lines.get(next) {
+        next += 1;
+    }
+    match lines.get(next) {
+        Some(Line::Instruction(_)) => Some(next),
+        _ => None,
+    }
+}
+
+fn forward_stores(lines: &mut Vec<Line>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < lines.len() {
+        if let Line::Instruction(store) = &lines[index] {
+            if store.op == "addi" && store.operands[1] == "0" {
+                if let Some(next) = next_instruction(lines, index) {
+                    if matches!(&lines[next], Line::Instruction(load) if is_move(load, &store.operands[2], &store.operands[0])) {
+                        lines.remove(next);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        index += 1;
+    }
+    changed
+}
+
+// Comparisons park the left side in r2 while the right side is worked out
+// in r0, then move it on to r1. When the right side never touches r1 the
+// left side can go straight there. r2 never carries a value any further
+// than this, so nothing else misses it.
+fn fold_shuffles(lines: &mut Vec<Line>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < lines.len() {
+        if matches!(&lines[index], Line::Instruction(i) if is_move(i, "r0", "r2")) {
+            let mut next = index;
+            while let Some(found) = next_instruction(lines, next) {
+                let Line::Instruction(instruction) = &lines[found] else { break };
+                if is_move(instruction, "r2", "r1") {
+                    if let Line::Instruction(park) = &mut lines[index] {
+                        park.operands[2] = String::from("r1");
+                    }
+                    lines.remove(found);
+                    changed = true;
+                    break;
+                }
+                let touches = |register| reads(instruction, register) || writes(instruction) == Some(register);
+                if is_jump(instruction) || touches("r1") || touches("r2") {
+                    break;
+                }
+                next = found;
+            }
+        }
+        index += 1;
+    }
+    changed
+}
+
+fn remove_jumps_to_next(lines: &mut Vec<Line>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < lines.len() {
+        if let Line::Instruction(jump) = &lines[index] {
+            if let Some(target) = jump.target() {
+                let mut next = index + 1;
+                let mut lands_next = false;
+                while let Some(line) = lines.get(next) {
+                    match line {
+                        Line::Label(label) if label == target => lands_next = true,
+                        Line::Label(_) | Line::Comment(_) => {}
+                        Line::Instruction(_) => break,
+                    }
+                    next += 1;
+                }
+                if lands_next && jump.op != "call" {
+                    lines.remove(index);
+                    changed = true;
+                    continue;
+                }
+            }
+        }
+        index += 1;
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `name:` is a label and `; text` a comment, anything else an instruction
+    fn lines(code: &[&str]) -> Vec<Line> {
+        code.iter().map(|line| match (line.strip_suffix(':'), line.strip_prefix("; ")) {
+            (Some(label), _) => Line::Label(label.to_string()),
+            (_, Some(text)) => Line::Comment(text.to_string()),
+            _ => Line::Instruction(Instruction::parse(line)),
+        }).collect()
+    }
+
+    fn optimized(code: &[&str]) -> Vec<Line> {
+        let mut code = lines(code);
+        optimize(&mut code);
+        code
+    }
+
+    #[test]
+    fn bp_is_only_set_again_after_a_label_or_call() {
+        let code = ["set bp 0 bp", "addi r0 0 ram", "set bp 0 bp", "addi ram 0 r1", "top:", "set bp 0 bp", "call 0 0 @sub", "set bp 0 bp", "sub:"];
+        assert_eq!(optimized(&code), lines(&["set bp 0 bp", "addi r0 0 ram", "addi ram 0 r1", "top:", "set bp 0 bp", "call 0 0 @sub", "set bp 0 bp", "sub:"]));
+    }
+
+    #[test]
+    fn stores_are_not_loaded_straight_back() {
+        assert_eq!(optimized(&["addi r0 0 ram", "; note", "addi ram 0 r0", "addi r0 0 r0"]), lines(&["addi r0 0 ram", "; note"]));
+        assert_eq!(optimized(&["addi r0 0 ram", "addi ram 0 r1"]), lines(&["addi r0 0 ram", "addi ram 0 r1"]));
+    }
+
+    #[test]
+    fn comparisons_move_the_left_side_straight_to_r1() {
+        let code = ["addi r0 0 r2", "set r0 5 r0", "addi r2 0 r1", "jlt r1 r0 @yes", "ret 0 0 0", "yes:"];
+        assert_eq!(optimized(&code), lines(&["addi r0 0 r1", "set r0 5 r0", "jlt r1 r0 @yes", "ret 0 0 0", "yes:"]));
+        // Unless the right side uses r1 itself
+        let code = ["addi r0 0 r2", "set r1 5 r1", "addi r2 0 r1", "set r0 1 r0"];
+        assert_eq!(optimized(&code), lines(&code));
+    }
+
+    #[test]
+    fn jumps_to_the_next_instruction_go() {
+        assert_eq!(optimized(&["jmp 0 0 @next", "; note", "next:", "ret 0 0 0"]), lines(&["; note", "next:", "ret 0 0 0"]));
+        assert_eq!(optimized(&["call 0 0 @next", "next:", "ret 0 0 0"]), lines(&["call 0 0 @next", "next:", "ret 0 0 0"]));
+    }
+
+    #[test]
+    fn the_report_counts_instructions_only() {
+        let mut code = lines(&["set bp 0 bp", "; note", "set bp 0 bp", "done:", "addi r0 0 r0", "ret 0 0 0"]);
+        assert_eq!(optimize(&mut code), Report { before: 4, after: 2 });
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use crate::ir::{Cfg, Terminator, Value};
+
+// r0, r1 and r2 are the code generator's scratch registers, so variables can
+// only be given the ones after them
+pub const SCRATCH_REGISTERS: usize = 3;
+
+// Which variables live in a register instead of ram, e.g. "x" -> "r3"
+pub type Allocation = HashMap<String, String>;
+
+// Linear scan register allocation over the instructions of a program, in
+// the order its blocks are laid out.
+//
+// Each variable is live from the first instruction that mentions it to the
+// last. Anything live somewhere inside a loop - any jump back to an earlier
+// block, or everything from a `gosub` on, since the subroutine may be
+// anywhere - is kept live for the whole loop. Variables whose intervals
+// don't overlap can then share a register. When registers run out the least
+// used variable stays in ram, where uses inside loops count ten times as much
+// per level of nesting.
+//
+// A variable that can be read before anything has written it, like one only
+// set inside an `if`, stays in ram, which starts out as zero where the
+// register it was given might not.
+pub fn allocate(cfg: &Cfg, registers: usize) -> Allocation {
+    let mut scan = Scan::default();
+    scan.cfg(cfg);
+    let unwritten = read_before_written(cfg);
+    let intervals: Vec<Interval> = scan.intervals().into_iter().filter(|interval| !unwritten.contains(&interval.name)).collect();
+    let mut free: Vec<String> = (SCRATCH_REGISTERS..registers).rev().map(|r| format!("r{}", r)).collect();
+    let mut active: Vec<&Interval> = Vec::new();
+    let mut allocation = Allocation::new();
+    for interval in &intervals {
+        active.retain(|other| {
+            if other.end < interval.start {
+                free.push(allocation[&other.name].clone());
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(register) = free.pop() {
+            allocation.insert(interval.name.clone(), register);
+            active.push(interval);
+            continue;
+        }
+        // Out of registers: the coldest of the active variables gives its
+        // register up, unless this one is colder still
+        let coldest = active.iter().enumerate().min_by_key(|(_, other)| other.weight).map(|(index, _)| index);
+        if let Some(index) = coldest {
+            if active[index].weight < interval.weight {
+                let spilled = active.remove(index);
+                let register = allocation.remove(&spilled.name).unwrap();
+                allocation.insert(interval.name.clone(), register);
+                active.push(interval);
+            }
+        }
+    }
+    allocation
+}
+
+// The variables some path from the start of the program reads before it
+// writes them
+fn read_before_written(cfg: &Cfg) -> HashSet<String> {
+    // What every path so far has written on entry to each block, where None
+    // is a block no path has reached yet
+    let mut written: Vec<Option<HashSet<&str>>> = vec![None; cfg.blocks.len()];
+    written[0] = Some(HashSet::new());
+    let mut unwritten = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &id in &cfg.order {
+            let Some(mut names) = written[id].clone() else { continue };
+            let block = &cfg.blocks[id];
+            let uses = block.insts.iter().map(|inst| (inst.uses(), inst.dest()))
+                .chain(std::iter::once((block.terminator.uses(), None)));
+            for (values, dest) in uses {
+                for value in values {
+                    if let Value::Var(name) = value {
+                        if !names.contains(name.as_str()) {
+                            unwritten.insert(name.clone());
+                        }
+                    }
+                }
+                if let Some(Value::Var(name)) = dest {
+                    names.insert(name);
+                }
+            }
+            for successor in block.terminator.successors() {
+                let merged = match &written[successor] {
+                    Some(old) => old.intersection(&names).copied().collect(),
+                    None => names.clone(),
+                };
+                if written[successor].as_ref() != Some(&merged) {
+                    written[successor] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+    unwritten
+}
+
+struct Interval {
+    name: String,
+    start: usize,
+    end: usize,
+    weight: u64,
+}
+
+#[derive(Default)]
+struct Scan {
+    position: usize,
+    uses: HashMap<String, (usize, usize, u64)>,
+    order: Vec<String>,
+    loops: Vec<(usize, usize)>,
+}
+
+impl Scan {
+    fn cfg(&mut self, cfg: &Cfg) {
+        // Where each block starts and ends
+        let mut spans = HashMap::new();
+        for &block in &cfg.order {
+            let start = self.position + 1;
+            self.position += cfg.blocks[block].insts.len() + 1;
+            spans.insert(block, (start, self.position));
+        }
+        let mut back_edges = Vec::new();
+        let mut gosubs = Vec::new();
+        for &block in &cfg.order {
+            let (_, end) = spans[&block];
+            let terminator = &cfg.blocks[block].terminator;
+            if let Terminator::Call { target, .. } = terminator {
+                gosubs.push((spans[target].0.min(end), self.position));
+            }
+            for successor in terminator.successors() {
+                let (start, _) = spans[&successor];
+                if start <= end {
+                    back_edges.push((start, end));
+                }
+            }
+        }
+        for &block in &cfg.order {
+            let (start, _) = spans[&block];
+            let block = &cfg.blocks[block];
+            let mut mentions: Vec<Vec<&Value>> = block.insts.iter().map(|inst| inst.dest().into_iter().chain(inst.uses()).collect()).collect();
+            mentions.push(block.terminator.uses());
+            for (offset, values) in mentions.into_iter().enumerate() {
+                let position = start + offset;
+                let depth = back_edges.iter().filter(|&&(start, end)| start <= position && position <= end).count() as u32;
+                for value in values {
+                    if let Value::Var(name) = value {
+                        self.mention(name, position, depth);
+                    }
+                }
+            }
+        }
+        self.loops = back_edges;
+        self.loops.extend(gosubs);
+    }
+
+    fn mention(&mut self, name: &str, position: usize, depth: u32) {
+        let weight = 10u64.saturating_pow(depth.min(6));
+        match self.uses.get_mut(name) {
+            Some((_, end, total)) => {
+                *end = position;
+                *total += weight;
+            }
+            None => {
+                self.uses.insert(name.to_string(), (position, position, weight));
+                self.order.push(name.to_string());
+            }
+        }
+    }
+
+    // Live intervals sorted by where they start, grown until no loop is
+    // only partly covered by one
+    fn intervals(&self) -> Vec<Interval> {
+        let mut intervals: Vec<Interval> = self.order.iter().map(|name| {
+            let (start, end, weight) = self.uses[name];
+            Interval { name: name.clone(), start, end, weight }
+        }).collect();
+        for interval in &mut intervals {
+            loop {
+                let mut grown = false;
+                for &(start, end) in &self.loops {
+                    let overlaps = interval.start <= end && start <= interval.end;
+                    if overlaps && (start < interval.start || end > interval.end) {
+                        interval.start = interval.start.min(start);
+                        interval.end = interval.end.max(end);
+                        grown = true;
+                    }
+                }
+                if !grown {
+                    break;
+                }
+            }
+        }
+        intervals.sort_by_key(|interval| interval.start);
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lower, Options};
+
+    fn allocated(source: &str) -> Vec<String> {
+        let cfg = lower(source, &Options::default()).unwrap();
+        let mut names: Vec<String> = allocate(&cfg, 16).into_keys().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn variables_read_before_they_are_written_stay_in_ram() {
+        assert_eq!(allocated("let a = 0\nif a == 1 then\nlet b = 5\nend if\nlet c = 7\nlet d = b + c\n"), ["a", "c", "d"]);
+        assert_eq!(allocated("let i = 0\nwhile i < 3 do\nlet t = i * 2\ni = i + t\nend while\n"), ["i", "t"]);
+    }
+
+    #[test]
+    fn variables_skipped_by_a_jump_stay_in_ram() {
+        assert_eq!(allocated("10 LET X = 1\n20 IF X THEN 50\n30 LET B = 1\n40 LET C = B\n50 LET D = B + C\n"), ["d", "x"]);
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use crate::ast::BinaryOp;
+use crate::backend::{self, symbol, Backend};
+use crate::ir::{BlockId, Cfg, Value};
+use crate::regalloc::{Allocation, SCRATCH_REGISTERS};
+
+// Lowers the blocks of a program to RV32I assembly for Linux, in GNU as
+// syntax. Registers follow the RISC-V calling convention:
+//
+// - variables the register allocator keeps in registers get the saved
+//   registers s1-s11, which the runtime never touches, and the rest live in
+//   .data
+// - t0-t2 are scratch, like r0-r2 on the register machine, and temporaries
+//   get t3-t6 and a2-a7
+// - the runtime routines take their arguments in a0 and a1 and return in a0
+// - a `gosub` pushes the address to come back to on the stack, and s0 holds
+//   where the stack started so a `return` without one can be caught
+//
+// RV32I has no multiply or divide, so the runtime does those in software,
+// along with print and input through Linux system calls.
+
+// Saved registers the allocator can hand out, s1 to s11
+pub const REGISTERS: usize = SCRATCH_REGISTERS + 11;
+
+const TEMPORARIES: [&str; 10] = ["t3", "t4", "t5", "t6", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+pub fn generate(cfg: &Cfg, allocation: &Allocation) -> String {
+    // The allocator numbers registers from r3, which become s1 and up
+    let registers: HashMap<String, String> = allocation.iter()
+        .map(|(name, register)| {
+            let index: usize = register[1..].parse().unwrap();
+            (name.clone(), format!("s{}", index - SCRATCH_REGISTERS + 1))
+        })
+        .collect();
+    let mut riscv = Riscv { lines: Vec::new(), registers, temps: HashMap::new(), free: Vec::new(), calls: 0 };
+    riscv.directive(".text");
+    riscv.directive(".globl _start");
+    riscv.label("_start");
+    riscv.code("mv s0, sp".to_string());
+    let mut saved: Vec<&String> = riscv.registers.values().collect();
+    saved.sort_by_key(|register| register[1..].parse::<usize>().unwrap());
+    saved.dedup();
+    let zeroed: Vec<String> = saved.iter().map(|register| format!("li {}, 0", register)).collect();
+    for line in zeroed {
+        riscv.code(line);
+    }
+    backend::emit(cfg, &mut riscv);
+    riscv.label(".Lend");
+    riscv.code("li a0, 0".to_string());
+    riscv.code("li a7, 93".to_string());
+    riscv.code("ecall".to_string());
+    let mut assembly = riscv.lines.join("\n");
+    assembly.push('\n');
+    assembly.push_str(RUNTIME);
+    let memory: Vec<&String> = cfg.variables.iter().map(|variable| &variable.name).filter(|name| !riscv.registers.contains_key(*name)).collect();
+    if !memory.is_empty() {
+        assembly.push_str("\n    .data\n    .p2align 2\n");
+        for name in memory {
+            assembly.push_str(&format!("{}:\n    .word 0\n", symbol(name)));
+        }
+    }
+    assembly
+}
+
+fn block_label(block: BlockId) -> String {
+    format!(".LB{}", block)
+}
+
+fn fits_immediate(value: i64) -> bool {
+    (-2048..2048).contains(&value)
+}
+
+struct Riscv {
+    lines: Vec<String>,
+    registers: HashMap<String, String>,
+    // Which register each temporary waiting to be used is in, and the
+    // registers left for more
+    temps: HashMap<usize, &'static str>,
+    free: Vec<&'static str>,
+    // How many calls have been made, which numbers the places they come
+    // back to
+    calls: usize,
+}
+
+impl Riscv {
+    fn code(&mut self, code: String) {
+        self.lines.push(format!("    {}", code));
+    }
+
+    fn directive(&mut self, directive: &str) {
+        self.lines.push(format!("    {}", directive));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(format!("{}:", label));
+    }
+
+    // A register holding the value, loading it into `scratch` if need be.
+    // A temporary is used up by reading it.
+    fn read(&mut self, value: &Value, scratch: &str) -> String {
+        match value {
+            Value::Const(0) => String::from("zero"),
+            Value::Const(value) => {
+                self.code(format!("li {}, {}", scratch, *value as i32));
+                scratch.to_string()
+            }
+            Value::Var(name) => match self.registers.get(name) {
+                Some(register) => register.clone(),
+                None => {
+                    self.code(format!("lw {}, {}", scratch, symbol(name)));
+                    scratch.to_string()
+                }
+            },
+            Value::Temp(temp) => {
+                let register = self.temps.remove(temp).unwrap_or_else(|| panic!("Temporary %{} is not in a register", temp));
+                self.free.push(register);
+                register.to_string()
+            }
+        }
+    }
+
+    // The register to work out a value for `dest` in
+    fn target(&mut self, dest: &Value) -> String {
+        match dest {
+            Value::Var(name) => self.registers.get(name).cloned().unwrap_or_else(|| String::from("t0")),
+            Value::Temp(temp) => {
+                let register = self.free.pop().expect("Out of registers for temporaries");
+                self.temps.insert(*temp, register);
+                register.to_string()
+            }
+            Value::Const(_) => panic!("Cannot assign to a constant"),
+        }
+    }
+
+    // Writes a value worked out for a variable in memory back to it
+    fn store(&mut self, dest: &Value, register: &str) {
+        if let Value::Var(name) = dest {
+            if !self.registers.contains_key(name) {
+                self.code(format!("sw {}, {}, t1", register, symbol(name)));
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, left: &Value, right: &Value, dest: &Value) {
+        let a = self.read(left, "t0");
+        // Most operations can take a small constant on the right as it is
+        let immediate = match (op, right) {
+            (BinaryOp::Add | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor, Value::Const(value)) if fits_immediate(*value) => Some(*value),
+            (BinaryOp::Sub, Value::Const(value)) if fits_immediate(-*value) => Some(-*value),
+            (BinaryOp::Shl, Value::Const(value)) => Some(value & 31),
+            _ => None,
+        };
+        if let Some(value) = immediate {
+            let target = self.target(dest);
+            let instruction = match op {
+                BinaryOp::Add | BinaryOp::Sub => "addi",
+                BinaryOp::And => "andi",
+                BinaryOp::Or  => "ori",
+                BinaryOp::Xor => "xori",
+                _             => "slli",
+            };
+            self.code(format!("{} {}, {}, {}", instruction, target, a, value));
+            self.store(dest, &target);
+            return;
+        }
+        let b = self.read(right, "t1");
+        let target = self.target(dest);
+        match op {
+            BinaryOp::Add => self.code(format!("add {}, {}, {}", target, a, b)),
+            BinaryOp::Sub => self.code(format!("sub {}, {}, {}", target, a, b)),
+            BinaryOp::And => self.code(format!("and {}, {}, {}", target, a, b)),
+            BinaryOp::Or  => self.code(format!("or {}, {}, {}", target, a, b)),
+            BinaryOp::Xor => self.code(format!("xor {}, {}, {}", target, a, b)),
+            BinaryOp::Shl => self.code(format!("sll {}, {}, {}", target, a, b)),
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => {
+                self.code(format!("mv a0, {}", a));
+                self.code(format!("mv a1, {}", b));
+                let routine = match op {
+                    BinaryOp::Mul => "basic_mul",
+                    BinaryOp::Div => "basic_div",
+                    _             => "basic_rem",
+                };
+                self.code(format!("call {}", routine));
+                self.code(format!("mv {}, a0", target));
+            }
+            // A comparison leaves -1 (true) or 0 (false), like traditional
+            // BASIC
+            _ => {
+                match op {
+                    BinaryOp::Equal | BinaryOp::NotEqual => self.code(format!("sub {}, {}, {}", target, a, b)),
+                    BinaryOp::Less | BinaryOp::GreaterEqual => self.code(format!("slt {}, {}, {}", target, a, b)),
+                    _ => self.code(format!("slt {}, {}, {}", target, b, a)),
+                }
+                match op {
+                    BinaryOp::Equal => self.code(format!("seqz {}, {}", target, target)),
+                    BinaryOp::NotEqual => self.code(format!("snez {}, {}", target, target)),
+                    BinaryOp::GreaterEqual | BinaryOp::LessEqual => self.code(format!("xori {}, {}, 1", target, target)),
+                    _ => {}
+                }
+                self.code(format!("neg {}, {}", target, target));
+            }
+        }
+        self.store(dest, &target);
+    }
+}
+
+impl Backend for Riscv {
+    fn label(&mut self, block: BlockId) {
+        self.label(&block_label(block));
+        self.temps.clear();
+        self.free = TEMPORARIES.iter().rev().copied().collect();
+    }
+
+    fn copy(&mut self, dest: &Value, src: &Value) {
+        let source = self.read(src, "t0");
+        let target = self.target(dest);
+        if source != target {
+            self.code(format!("mv {}, {}", target, source));
+        }
+        self.store(dest, &target);
+    }
+
+    fn arithmetic(&mut self, op: BinaryOp, dest: &Value, left: &Value, right: &Value) {
+        self.binary(op, left, right, dest);
+    }
+
+    fn print(&mut self, value: &Value) {
+        let register = self.read(value, "a0");
+        if register != "a0" {
+            self.code(format!("mv a0, {}", register));
+        }
+        self.code("call basic_print".to_string());
+    }
+
+    fn input(&mut self, dest: &Value) {
+        self.code("call basic_input".to_string());
+        let target = self.target(dest);
+        self.code(format!("mv {}, a0", target));
+        self.store(dest, &target);
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.lines.push(format!("    # {}", text).trim_end().to_string());
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.code(format!("j {}", block_label(target)));
+    }
+
+    fn branch(&mut self, op: BinaryOp, left: &Value, right: &Value, target: BlockId) {
+        let a = self.read(left, "t0");
+        let b = self.read(right, "t1");
+        let (instruction, a, b) = match op {
+            BinaryOp::Equal        => ("beq", a, b),
+            BinaryOp::NotEqual     => ("bne", a, b),
+            BinaryOp::Less         => ("blt", a, b),
+            BinaryOp::GreaterEqual => ("bge", a, b),
+            BinaryOp::Greater      => ("blt", b, a),
+            BinaryOp::LessEqual    => ("bge", b, a),
+            _ => panic!("Unknown condition {}", op.symbol()),
+        };
+        self.code(format!("{} {}, {}, {}", instruction, a, b, block_label(target)));
+    }
+
+    fn call(&mut self, target: BlockId, _after: BlockId) {
+        self.calls += 1;
+        let back = format!(".LR{}", self.calls);
+        self.code(format!("la t0, {}", back));
+        self.code("addi sp, sp, -16".to_string());
+        self.code("sw t0, 0(sp)".to_string());
+        self.code(format!("j {}", block_label(target)));
+        self.label(&back);
+    }
+
+    fn ret(&mut self) {
+        self.code("beq sp, s0, basic_return_without_call".to_string());
+        self.code("lw t0, 0(sp)".to_string());
+        self.code("addi sp, sp, 16".to_string());
+        self.code("jr t0".to_string());
+    }
+
+    fn halt(&mut self) {
+        self.code("j .Lend".to_string());
+    }
+}
+
+// basic_mul, basic_div and basic_rem work on a0 and a1 and leave the result
+// in a0. Like the rest of the runtime they only use t0-t2 besides, saving
+// anything else they need, so temporaries survive a call. Division rounds
+// towards zero, the smallest number divided by -1 wraps around, and
+// dividing by zero stops the program with an error, as on the emulator.
+// basic_print writes a0 as a decimal number and a newline. basic_input
+// reads a number into a0: anything up to the next digit or `-` is skipped,
+// the number ends at the first character that isn't a digit, and at the end
+// of the input it reads 0.
+const RUNTIME: &str = "
+basic_mul:
+    mv t0, a0
+    li a0, 0
+.Lmul_bit:
+    andi t1, a1, 1
+    beqz t1, .Lmul_next
+    add a0, a0, t0
+.Lmul_next:
+    slli t0, t0, 1
+    srli a1, a1, 1
+    bnez a1, .Lmul_bit
+    ret
+
+# Unsigned a0 / a1, leaving the quotient in a0 and the remainder in a1
+basic_udiv:
+    li t0, 32
+    li t1, 0
+.Ludiv_bit:
+    srli t2, a0, 31
+    slli t1, t1, 1
+    or t1, t1, t2
+    slli a0, a0, 1
+    bltu t1, a1, .Ludiv_next
+    sub t1, t1, a1
+    ori a0, a0, 1
+.Ludiv_next:
+    addi t0, t0, -1
+    bnez t0, .Ludiv_bit
+    mv a1, t1
+    ret
+
+basic_div:
+    beqz a1, basic_division_by_zero
+    addi sp, sp, -16
+    sw ra, 12(sp)
+    xor t0, a0, a1
+    sw t0, 8(sp)
+    srai t0, a0, 31
+    xor a0, a0, t0
+    sub a0, a0, t0
+    srai t0, a1, 31
+    xor a1, a1, t0
+    sub a1, a1, t0
+    call basic_udiv
+    lw t0, 8(sp)
+    srai t0, t0, 31
+    xor a0, a0, t0
+    sub a0, a0, t0
+    lw ra, 12(sp)
+    addi sp, sp, 16
+    ret
+
+basic_rem:
+    beqz a1, basic_division_by_zero
+    addi sp, sp, -16
+    sw ra, 12(sp)
+    sw a0, 8(sp)
+    srai t0, a0, 31
+    xor a0, a0, t0
+    sub a0, a0, t0
+    srai t0, a1, 31
+    xor a1, a1, t0
+    sub a1, a1, t0
+    call basic_udiv
+    lw t0, 8(sp)
+    srai t0, t0, 31
+    xor a0, a1, t0
+    sub a0, a0, t0
+    lw ra, 12(sp)
+    addi sp, sp, 16
+    ret
+
+basic_print:
+    addi sp, sp, -48
+    sw ra, 44(sp)
+    sw a2, 40(sp)
+    sw a7, 36(sp)
+    addi a2, sp, 32
+    li t0, 10
+    sb t0, 0(a2)
+    mv a7, a0
+    srai t0, a0, 31
+    xor a0, a0, t0
+    sub a0, a0, t0
+.Lprint_digit:
+    li a1, 10
+    call basic_udiv
+    addi a1, a1, 48
+    addi a2, a2, -1
+    sb a1, 0(a2)
+    bnez a0, .Lprint_digit
+    bgez a7, .Lprint_write
+    li t0, 45
+    addi a2, a2, -1
+    sb t0, 0(a2)
+.Lprint_write:
+    li a0, 1
+    mv a1, a2
+    addi a2, sp, 33
+    sub a2, a2, a1
+    li a7, 64
+    ecall
+    lw a7, 36(sp)
+    lw a2, 40(sp)
+    lw ra, 44(sp)
+    addi sp, sp, 48
+    ret
+
+basic_input:
+    addi sp, sp, -32
+    sw ra, 28(sp)
+    sw a2, 24(sp)
+    sw a7, 20(sp)
+    sw zero, 16(sp)
+.Linput_skip:
+    sw zero, 12(sp)
+.Linput_sign:
+    call basic_getc
+    bltz a0, .Linput_done
+    li t0, 45
+    bne a0, t0, .Linput_first
+    li t0, 1
+    sw t0, 12(sp)
+    j .Linput_sign
+.Linput_first:
+    addi a0, a0, -48
+    li t0, 10
+    bgeu a0, t0, .Linput_skip
+.Linput_digit:
+    lw t0, 16(sp)
+    slli t1, t0, 3
+    slli t0, t0, 1
+    add t0, t0, t1
+    add t0, t0, a0
+    sw t0, 16(sp)
+    call basic_getc
+    addi a0, a0, -48
+    li t0, 10
+    bltu a0, t0, .Linput_digit
+.Linput_done:
+    lw a0, 16(sp)
+    lw t0, 12(sp)
+    beqz t0, .Linput_return
+    neg a0, a0
+.Linput_return:
+    lw a7, 20(sp)
+    lw a2, 24(sp)
+    lw ra, 28(sp)
+    addi sp, sp, 32
+    ret
+
+# The next byte of input in a0, or -1 at the end. Uses a1, a2 and a7 too.
+basic_getc:
+    addi sp, sp, -16
+    li a0, 0
+    mv a1, sp
+    li a2, 1
+    li a7, 63
+    ecall
+    li t0, 1
+    bne a0, t0, .Lgetc_end
+    lbu a0, 0(sp)
+    addi sp, sp, 16
+    ret
+.Lgetc_end:
+    li a0, -1
+    addi sp, sp, 16
+    ret
+
+basic_division_by_zero:
+    la a1, .Ldivision_by_zero
+    li a2, 17
+    j basic_fail
+
+basic_return_without_call:
+    la a1, .Lreturn_without_call
+    li a2, 22
+    j basic_fail
+
+# Writes the message at a1, a2 bytes long, to stderr and exits with 1
+basic_fail:
+    li a0, 2
+    li a7, 64
+    ecall
+    li a0, 1
+    li a7, 93
+    ecall
+
+    .section .rodata
+.Ldivision_by_zero:
+    .ascii \"Division by zero\\n\"
+.Lreturn_without_call:
+    .ascii \"Return without a call\\n\"
+";
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::{run_rv32, Rv32};
+    use crate::{compile, Options, Target};
+
+    fn run(source: &str, opt_level: u8, input: &str) -> Rv32 {
+        let options = Options { target: Target::Rv32, opt_level, ..Options::default() };
+        let assembly = compile(source, &options).unwrap().assembly;
+        run_rv32(&assembly, &mut input.as_bytes()).unwrap()
+    }
+
+    fn stdout(result: &Rv32) -> &str {
+        std::str::from_utf8(&result.stdout).unwrap()
+    }
+
+    #[test]
+    fn software_arithmetic_matches_the_emulator() {
+        let source = "let a = 0 - 7\nprint a * 6\nprint a / 2\nprint a % 2\nlet m = 0 - 2147483647 - 1\nlet n = 0 - 1\nprint m / n\nprint 2147483647 + 1\n";
+        for opt_level in 0..=2 {
+            let result = run(source, opt_level, "");
+            assert_eq!(stdout(&result), "-42\n-3\n-1\n-2147483648\n-2147483648\n");
+            assert_eq!(result.exit_code, 0);
+        }
+    }
+
+    #[test]
+    fn input_skips_to_the_next_number() {
+        let source = "let a = 0\nlet b = 0\nlet c = 0\ninput a\ninput b\ninput c\nprint a + b\nprint c\n";
+        assert_eq!(stdout(&run(source, 0, "x 12, -5")), "7\n0\n");
+    }
+
+    #[test]
+    fn variables_in_saved_registers_survive_gosubs() {
+        let source = "let i = 0\nlet s = 0\nwhile i < 10 do\ngosub add\ni = i + 1\nend while\nprint s\nend\nlabel add\ns = i * i + s\nreturn\n";
+        assert_eq!(stdout(&run(source, 2, "")), "285\n");
+    }
+
+    #[test]
+    fn failures_write_to_stderr_and_exit_with_1() {
+        let result = run("let a = 0\nprint 1\nprint 1 / a\n", 0, "");
+        assert_eq!((stdout(&result), result.stderr.as_slice(), result.exit_code), ("1\n", &b"Division by zero\n"[..], 1));
+        let result = run("print 2\nreturn\n", 0, "");
+        assert_eq!((stdout(&result), result.stderr.as_slice(), result.exit_code), ("2\n", &b"Return without a call\n"[..], 1));
+    }
+}
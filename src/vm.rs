@@ -0,0 +1,126 @@
+use crate::ast::BinaryOp;
+use crate::bytecode::{Chunk, Instruction};
+use crate::emulator::RuntimeError;
+
+// Calls host functions for `Vm::run`, given where a function is in the
+// chunk's function table and its arguments. What it gives back is pushed,
+// and an error stops the program.
+pub type Host<'a> = dyn FnMut(usize, &[i32]) -> Result<i32, String> + 'a;
+
+// Runs bytecode. The code is decoded and checked once when the VM is made,
+// so running it is a loop over instructions that can't go wrong except in
+// the ways the program itself can: dividing by zero, returning without a
+// call, running for too long, or a host function failing.
+#[derive(Clone, Debug)]
+pub struct Vm {
+    instructions: Vec<Instruction>,
+    // Where each instruction starts in the code, for errors
+    offsets: Vec<usize>,
+    symbols: Vec<(String, u16)>,
+    pub slots: Vec<i32>,
+    pub output: Vec<i64>,
+    pub steps: u64,
+    // Stops a program that runs for longer, when set
+    pub step_limit: Option<u64>,
+}
+
+impl Vm {
+    pub fn new(chunk: &Chunk) -> Result<Vm, RuntimeError> {
+        let (offsets, instructions) = chunk.instructions()?.into_iter().unzip();
+        Ok(Vm {
+            instructions,
+            offsets,
+            symbols: chunk.symbols.clone(),
+            slots: vec![0; chunk.slots as usize],
+            output: Vec::new(),
+            steps: 0,
+            step_limit: None,
+        })
+    }
+
+    // The value of a variable, by name
+    pub fn get(&self, name: &str) -> Option<i32> {
+        self.symbols.iter().find(|(other, _)| other == name).map(|(_, slot)| self.slots[*slot as usize])
+    }
+
+    // Sets a variable by name, and says whether there is one
+    pub fn set(&mut self, name: &str, value: i32) -> bool {
+        match self.symbols.iter().find(|(other, _)| other == name) {
+            Some((_, slot)) => {
+                self.slots[*slot as usize] = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Runs the program from the start, with variables as they are
+    pub fn run(&mut self, input: &mut dyn Iterator<Item = i64>, host: &mut Host) -> Result<(), RuntimeError> {
+        let mut stack: Vec<i32> = Vec::new();
+        let mut calls: Vec<usize> = Vec::new();
+        let mut pc = 0;
+        let error = |pc: usize, message: &str| RuntimeError { message: message.to_string(), address: self.offsets[pc] };
+        while let Some(&instruction) = self.instructions.get(pc) {
+            if self.step_limit == Some(self.steps) {
+                return Err(error(pc, &format!("Stopped after {} instructions", self.steps)));
+            }
+            self.steps += 1;
+            pc += 1;
+            match instruction {
+                Instruction::Halt => break,
+                Instruction::Push(value) => stack.push(value),
+                Instruction::Load(slot) => stack.push(self.slots[slot]),
+                Instruction::Store(slot) => self.slots[slot] = stack.pop().unwrap(),
+                Instruction::Print => self.output.push(stack.pop().unwrap() as i64),
+                Instruction::Input => stack.push(input.next().unwrap_or(0) as i32),
+                Instruction::Jump(target) => pc = target,
+                Instruction::Call(target) => {
+                    calls.push(pc);
+                    pc = target;
+                }
+                Instruction::Return => pc = calls.pop().ok_or_else(|| error(pc - 1, "Return without a call"))?,
+                Instruction::Pop => { stack.pop(); }
+                Instruction::Host(function, count) => {
+                    let arguments = stack.split_off(stack.len() - count);
+                    stack.push(host(function, &arguments).map_err(|message| error(pc - 1, &message))?);
+                }
+                Instruction::Operator(op) => {
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    stack.push(calculate(op, left, right).ok_or_else(|| error(pc - 1, "Division by zero"))?);
+                }
+                Instruction::Branch(op, target) => {
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    if calculate(op, left, right) != Some(0) {
+                        pc = target;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Works out an operator on two words, wrapping around like the other
+// targets. Shifting by 32 to 63 clears the word, as on the emulator.
+// Division by zero gives None.
+pub fn calculate(op: BinaryOp, left: i32, right: i32) -> Option<i32> {
+    Some(match op {
+        BinaryOp::Add => left.wrapping_add(right),
+        BinaryOp::Sub => left.wrapping_sub(right),
+        BinaryOp::Mul => left.wrapping_mul(right),
+        BinaryOp::Div => left.checked_div(right).or_else(|| (right == -1).then(|| left.wrapping_neg()))?,
+        BinaryOp::Mod => left.checked_rem(right).or_else(|| (right == -1).then_some(0))?,
+        BinaryOp::Xor => left ^ right,
+        BinaryOp::And => left & right,
+        BinaryOp::Or  => left | right,
+        BinaryOp::Shl => (left as i64).wrapping_shl(right as u32) as i32,
+        BinaryOp::Equal        => -((left == right) as i32),
+        BinaryOp::NotEqual     => -((left != right) as i32),
+        BinaryOp::Less         => -((left < right) as i32),
+        BinaryOp::Greater      => -((left > right) as i32),
+        BinaryOp::LessEqual    => -((left <= right) as i32),
+        BinaryOp::GreaterEqual => -((left >= right) as i32),
+    })
+}
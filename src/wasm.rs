@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use crate::ast::BinaryOp;
+use crate::backend::{self, Backend};
+use crate::ir::{BlockId, Cfg, Value};
+
+// Lowers the blocks of a program to a WebAssembly module through
+// `backend::emit`. Wasm only has structured control flow, so the blocks sit
+// in a loop around a switch: a local says which block runs next, a
+// `br_table` at the top of the loop jumps to it, and a block that goes
+// anywhere but the next one sets the local and branches back to the top. A
+// `gosub` pushes the block to come back to on a stack in linear memory. Variables are mutable i32 globals
+// and temporaries are locals, shared by ones that are never live at once.
+// The module imports `print` and `input` from "env" and exports `main`,
+// which runs the program.
+//
+// The module can be written out as WAT text or encoded as a .wasm binary.
+
+// Functions, in index order: the two imports, then main and the division
+// helper
+const PRINT: u32 = 0;
+const INPUT: u32 = 1;
+const DIVIDE: u32 = 3;
+
+// Locals of main before the temporaries
+const NEXT: u32 = 0;
+const STACK: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+    Const(i32),
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    // Instructions with no immediates, by name and opcode
+    Plain(&'static str, u8),
+    Load,
+    Store,
+    Call(u32),
+    Block,
+    Loop,
+    If,
+    End,
+    Br(u32),
+    BrTable(Vec<u32>, u32),
+    // Only written to WAT
+    Comment(String),
+}
+
+const ADD: Op = Op::Plain("i32.add", 0x6a);
+const SUB: Op = Op::Plain("i32.sub", 0x6b);
+const EQZ: Op = Op::Plain("i32.eqz", 0x45);
+const EQ: Op = Op::Plain("i32.eq", 0x46);
+const RETURN: Op = Op::Plain("return", 0x0f);
+const UNREACHABLE: Op = Op::Plain("unreachable", 0x00);
+
+fn operator(op: BinaryOp) -> Op {
+    match op {
+        BinaryOp::Add          => ADD,
+        BinaryOp::Sub          => SUB,
+        BinaryOp::Mul          => Op::Plain("i32.mul", 0x6c),
+        BinaryOp::Div          => Op::Call(DIVIDE),
+        BinaryOp::Mod          => Op::Plain("i32.rem_s", 0x6f),
+        BinaryOp::And          => Op::Plain("i32.and", 0x71),
+        BinaryOp::Or           => Op::Plain("i32.or", 0x72),
+        BinaryOp::Xor          => Op::Plain("i32.xor", 0x73),
+        BinaryOp::Shl          => Op::Plain("i32.shl", 0x74),
+        BinaryOp::Equal        => EQ,
+        BinaryOp::NotEqual     => Op::Plain("i32.ne", 0x47),
+        BinaryOp::Less         => Op::Plain("i32.lt_s", 0x48),
+        BinaryOp::Greater      => Op::Plain("i32.gt_s", 0x4a),
+        BinaryOp::LessEqual    => Op::Plain("i32.le_s", 0x4c),
+        BinaryOp::GreaterEqual => Op::Plain("i32.ge_s", 0x4e),
+    }
+}
+
+// i32.div_s traps on the smallest number divided by -1, where the emulator
+// wraps around, so that case is done as a subtraction. Dividing by zero
+// still traps.
+fn divide() -> Vec<Op> {
+    vec![
+        Op::LocalGet(1), Op::Const(-1), EQ, Op::If,
+        Op::Const(0), Op::LocalGet(0), SUB, RETURN,
+        Op::End,
+        Op::LocalGet(0), Op::LocalGet(1), Op::Plain("i32.div_s", 0x6d),
+    ]
+}
+
+#[derive(Clone, Debug)]
+pub struct Module {
+    variables: Vec<String>,
+    temps: u32,
+    main: Vec<Op>,
+}
+
+pub fn generate(cfg: &Cfg) -> Module {
+    let globals = cfg.variables.iter().enumerate().map(|(index, variable)| (variable.name.clone(), index as u32)).collect();
+    let position = cfg.order.iter().enumerate().map(|(index, &block)| (block, index as u32)).collect();
+    let (slots, temps) = cfg.temp_slots();
+    let blocks = cfg.order.len() as u32;
+    let mut wasm = Wasm { ops: Vec::new(), globals, position, slots, blocks, depth: 0 };
+    wasm.ops.push(Op::Loop);
+    for _ in 0..blocks {
+        wasm.ops.push(Op::Block);
+    }
+    wasm.ops.push(Op::LocalGet(NEXT));
+    wasm.ops.push(Op::BrTable((0..blocks).collect(), 0));
+    backend::emit(cfg, &mut wasm);
+    wasm.ops.push(Op::End);
+    Module { variables: cfg.variables.iter().map(|variable| variable.name.clone()).collect(), temps: temps as u32, main: wasm.ops }
+}
+
+struct Wasm {
+    ops: Vec<Op>,
+    globals: HashMap<String, u32>,
+    position: HashMap<BlockId, u32>,
+    // Which local each temporary is, after the ones before them
+    slots: HashMap<usize, usize>,
+    blocks: u32,
+    depth: u32,
+}
+
+impl Wasm {
+    fn get(&mut self, value: &Value) {
+        let op = match value {
+            Value::Const(value) => Op::Const(*value as i32),
+            Value::Var(name) => Op::GlobalGet(self.globals[name]),
+            Value::Temp(temp) => Op::LocalGet(self.temp(*temp)),
+        };
+        self.ops.push(op);
+    }
+
+    fn set(&mut self, dest: &Value) {
+        let op = match dest {
+            Value::Var(name) => Op::GlobalSet(self.globals[name]),
+            Value::Temp(temp) => Op::LocalSet(self.temp(*temp)),
+            Value::Const(_) => panic!("Cannot assign to a constant"),
+        };
+        self.ops.push(op);
+    }
+
+    fn temp(&self, temp: usize) -> u32 {
+        STACK + 1 + self.slots[&temp] as u32
+    }
+
+    // Carries on at `target` by going round the loop, from `depth` blocks
+    // in
+    fn goto(&mut self, target: BlockId, depth: u32) {
+        self.ops.push(Op::Const(self.position[&target] as i32));
+        self.ops.push(Op::LocalSet(NEXT));
+        self.ops.push(Op::Br(depth));
+    }
+}
+
+impl Backend for Wasm {
+    // Closes the block before, so the code that follows is where the
+    // `br_table` sends this block's position
+    fn label(&mut self, block: BlockId) {
+        self.ops.push(Op::End);
+        // How many blocks still enclose this one's code, and so how far it
+        // is to branch back to the top of the loop
+        self.depth = self.blocks - 1 - self.position[&block];
+    }
+
+    fn copy(&mut self, dest: &Value, src: &Value) {
+        self.get(src);
+        self.set(dest);
+    }
+
+    // Leaves -1 (true) or 0 (false) for a comparison, like traditional
+    // BASIC, or else the result of the arithmetic
+    fn arithmetic(&mut self, op: BinaryOp, dest: &Value, left: &Value, right: &Value) {
+        if op.is_relation() {
+            self.ops.push(Op::Const(0));
+        }
+        self.get(left);
+        self.get(right);
+        self.ops.push(operator(op));
+        if op.is_relation() {
+            self.ops.push(SUB);
+        }
+        self.set(dest);
+    }
+
+    fn print(&mut self, value: &Value) {
+        self.get(value);
+        self.ops.push(Op::Call(PRINT));
+    }
+
+    fn input(&mut self, dest: &Value) {
+        self.ops.push(Op::Call(INPUT));
+        self.set(dest);
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.ops.push(Op::Comment(text.to_string()));
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.goto(target, self.depth);
+    }
+
+    fn branch(&mut self, op: BinaryOp, left: &Value, right: &Value, target: BlockId) {
+        self.get(left);
+        self.get(right);
+        self.ops.push(operator(op));
+        self.ops.push(Op::If);
+        self.goto(target, self.depth + 1);
+        self.ops.push(Op::End);
+    }
+
+    // Pushes where to come back to and goes round the loop, so the jump to
+    // `after` that can follow is never reached
+    fn call(&mut self, target: BlockId, after: BlockId) {
+        self.ops.extend([Op::LocalGet(STACK), Op::Const(self.position[&after] as i32), Op::Store]);
+        self.ops.extend([Op::LocalGet(STACK), Op::Const(4), ADD, Op::LocalSet(STACK)]);
+        self.goto(target, self.depth);
+    }
+
+    // A return without a gosub traps
+    fn ret(&mut self) {
+        self.ops.extend([Op::LocalGet(STACK), EQZ, Op::If, UNREACHABLE, Op::End]);
+        self.ops.extend([Op::LocalGet(STACK), Op::Const(4), SUB, Op::LocalSet(STACK)]);
+        self.ops.extend([Op::LocalGet(STACK), Op::Load, Op::LocalSet(NEXT), Op::Br(self.depth)]);
+    }
+
+    fn halt(&mut self) {
+        self.ops.push(RETURN);
+    }
+}
+
+// Names that WAT can take as they are, which leaves out non-ASCII letters
+fn is_identifier(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || "_.".contains(c))
+}
+
+impl Module {
+    // The module in the WebAssembly text format
+    pub fn wat(&self) -> String {
+        let global = |index: u32| {
+            let name = &self.variables[index as usize];
+            if is_identifier(name) { format!("${}", name) } else { index.to_string() }
+        };
+        // Only main's own locals have names
+        let local = |index: u32, main: bool| match index {
+            NEXT if main => String::from("$next"),
+            STACK if main => String::from("$stack"),
+            _ => index.to_string(),
+        };
+        let mut wat = String::from("(module\n");
+        wat.push_str("  (import \"env\" \"print\" (func $print (param i32)))\n");
+        wat.push_str("  (import \"env\" \"input\" (func $input (result i32)))\n");
+        wat.push_str("  (memory 1)\n");
+        for (index, name) in self.variables.iter().enumerate() {
+            if is_identifier(name) {
+                wat.push_str(&format!("  (global ${} (mut i32) (i32.const 0))\n", name));
+            } else {
+                wat.push_str(&format!("  (global (;{};) (mut i32) (i32.const 0))\n", index));
+            }
+        }
+        wat.push_str("  (func $main (export \"main\")\n");
+        wat.push_str("    (local $next i32) (local $stack i32)");
+        if self.temps > 0 {
+            wat.push_str(&format!(" (local{})", " i32".repeat(self.temps as usize)));
+        }
+        wat.push('\n');
+        let write = |wat: &mut String, ops: &[Op], main: bool| {
+            let mut indent = 2;
+            for op in ops {
+                if *op == Op::End {
+                    indent -= 1;
+                }
+                let text = match op {
+                    Op::Const(value) => format!("i32.const {}", value),
+                    Op::LocalGet(index) => format!("local.get {}", local(*index, main)),
+                    Op::LocalSet(index) => format!("local.set {}", local(*index, main)),
+                    Op::GlobalGet(index) => format!("global.get {}", global(*index)),
+                    Op::GlobalSet(index) => format!("global.set {}", global(*index)),
+                    Op::Plain(name, _) => name.to_string(),
+                    Op::Load => String::from("i32.load"),
+                    Op::Store => String::from("i32.store"),
+                    Op::Call(PRINT) => String::from("call $print"),
+                    Op::Call(INPUT) => String::from("call $input"),
+                    Op::Call(_) => String::from("call $divide"),
+                    Op::Block => String::from("block"),
+                    Op::Loop => String::from("loop"),
+                    Op::If => String::from("if"),
+                    Op::End => String::from("end"),
+                    Op::Br(depth) => format!("br {}", depth),
+                    Op::BrTable(targets, default) => {
+                        let targets: Vec<String> = targets.iter().map(u32::to_string).collect();
+                        format!("br_table {} {}", targets.join(" "), default)
+                    }
+                    Op::Comment(text) => format!(";; {}", text).trim_end().to_string(),
+                };
+                wat.push_str(&format!("{}{}\n", "  ".repeat(indent), text));
+                if matches!(op, Op::Block | Op::Loop | Op::If) {
+                    indent += 1;
+                }
+            }
+        };
+        write(&mut wat, &self.main, true);
+        wat.push_str("  )\n");
+        wat.push_str("  (func $divide (param i32 i32) (result i32)\n");
+        write(&mut wat, &divide(), false);
+        wat.push_str("  )\n");
+        wat.push_str(")\n");
+        wat
+    }
+
+    // The module as a .wasm binary
+    pub fn encode(&self) -> Vec<u8> {
+        let mut binary = b"\0asm".to_vec();
+        binary.extend([1, 0, 0, 0]);
+        // Types: print, input, main and divide
+        section(&mut binary, 1, &vector(vec![
+            vec![0x60, 1, 0x7f, 0],
+            vec![0x60, 0, 1, 0x7f],
+            vec![0x60, 0, 0],
+            vec![0x60, 2, 0x7f, 0x7f, 1, 0x7f],
+        ]));
+        let import = |name: &str, kind: u8| [string("env"), string(name), vec![0, kind]].concat();
+        section(&mut binary, 2, &vector(vec![import("print", 0), import("input", 1)]));
+        section(&mut binary, 3, &vector(vec![vec![2], vec![3]]));
+        // One page of memory, for the gosub stack
+        section(&mut binary, 5, &vector(vec![vec![0, 1]]));
+        let global = vec![0x7f, 1, 0x41, 0, 0x0b];
+        section(&mut binary, 6, &vector(vec![global; self.variables.len()]));
+        section(&mut binary, 7, &vector(vec![[string("main"), vec![0, 2]].concat()]));
+        let main = [unsigned(1), unsigned(STACK + 1 + self.temps), vec![0x7f], code(&self.main)].concat();
+        let divide = [unsigned(0), code(&divide())].concat();
+        section(&mut binary, 10, &vector(vec![sized(main), sized(divide)]));
+        binary
+    }
+}
+
+fn unsigned(mut value: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn signed(mut value: i32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn sized(bytes: Vec<u8>) -> Vec<u8> {
+    [unsigned(bytes.len() as u32), bytes].concat()
+}
+
+fn string(text: &str) -> Vec<u8> {
+    sized(text.as_bytes().to_vec())
+}
+
+fn vector(items: Vec<Vec<u8>>) -> Vec<u8> {
+    [unsigned(items.len() as u32), items.concat()].concat()
+}
+
+fn section(binary: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    binary.push(id);
+    binary.extend(sized(contents.to_vec()));
+}
+
+// A function body's instructions, with the `end` that closes it
+fn code(ops: &[Op]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for op in ops {
+        match op {
+            Op::Const(value) => { bytes.push(0x41); bytes.extend(signed(*value)); }
+            Op::LocalGet(index) => { bytes.push(0x20); bytes.extend(unsigned(*index)); }
+            Op::LocalSet(index) => { bytes.push(0x21); bytes.extend(unsigned(*index)); }
+            Op::GlobalGet(index) => { bytes.push(0x23); bytes.extend(unsigned(*index)); }
+            Op::GlobalSet(index) => { bytes.push(0x24); bytes.extend(unsigned(*index)); }
+            Op::Plain(_, opcode) => bytes.push(*opcode),
+            // Aligned to 4 bytes, with no offset
+            Op::Load => bytes.extend([0x28, 2, 0]),
+            Op::Store => bytes.extend([0x36, 2, 0]),
+            Op::Call(function) => { bytes.push(0x10); bytes.extend(unsigned(*function)); }
+            Op::Block => bytes.extend([0x02, 0x40]),
+            Op::Loop => bytes.extend([0x03, 0x40]),
+            Op::If => bytes.extend([0x04, 0x40]),
+            Op::End => bytes.push(0x0b),
+            Op::Br(depth) => { bytes.push(0x0c); bytes.extend(unsigned(*depth)); }
+            Op::BrTable(targets, default) => {
+                bytes.push(0x0e);
+                bytes.extend(vector(targets.iter().map(|&target| unsigned(target)).collect()));
+                bytes.extend(unsigned(*default));
+            }
+            Op::Comment(_) => {}
+        }
+    }
+    bytes.push(0x0b);
+    bytes
+}
@@ -0,0 +1,337 @@
+use crate::ast::BinaryOp;
+use crate::backend::{self, symbol, Backend};
+use crate::ir::{BlockId, Cfg, Value};
+
+// Lowers the blocks of a program to GNU as x86-64 assembly for Linux, to be
+// built with `as program.s -o program.o && ld program.o -o program`. Words
+// are 32 bits, like the register machine's. Variables live in .bss and
+// temporaries in a frame below rbp, and every instruction is worked out in
+// eax with ecx and edx as scratch, so nothing is kept in registers from one
+// instruction to the next. A `gosub` is a native call that counts how deep
+// it is, so a `return` without one fails instead of crashing. Print, input
+// and division go through a small runtime that talks to the kernel
+// directly, and fails the way the C target does.
+pub fn generate(cfg: &Cfg) -> String {
+    let temps = cfg.temps();
+    let mut x86 = X86 { lines: Vec::new() };
+    x86.directive(".text");
+    x86.directive(".globl _start");
+    x86.label("_start");
+    x86.code("movq %rsp, %rbp".to_string());
+    if temps > 0 {
+        // Keeps rsp 16-byte aligned, as it is on entry
+        x86.code(format!("subq ${}, %rsp", (4 * temps).div_ceil(16) * 16));
+    }
+    backend::emit(cfg, &mut x86);
+    x86.label(".Lend");
+    x86.code("movl $60, %eax".to_string());
+    x86.code("xorl %edi, %edi".to_string());
+    x86.code("syscall".to_string());
+    let mut assembly = x86.lines.join("\n");
+    assembly.push('\n');
+    assembly.push_str(RUNTIME);
+    if !cfg.variables.is_empty() {
+        assembly.push_str("\n    .bss\n    .p2align 2\n");
+        for variable in &cfg.variables {
+            assembly.push_str(&format!("{}:\n    .zero 4\n", symbol(&variable.name)));
+        }
+    }
+    assembly
+}
+
+fn block_label(block: BlockId) -> String {
+    format!(".LB{}", block)
+}
+
+// Where a value can be read from as an instruction's source operand
+fn operand(value: &Value) -> String {
+    match value {
+        Value::Const(value) => format!("${}", *value as i32),
+        Value::Var(name) => format!("{}(%rip)", symbol(name)),
+        Value::Temp(temp) => format!("-{}(%rbp)", 4 * (temp + 1)),
+    }
+}
+
+fn condition(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Equal        => "e",
+        BinaryOp::NotEqual     => "ne",
+        BinaryOp::Less         => "l",
+        BinaryOp::Greater      => "g",
+        BinaryOp::LessEqual    => "le",
+        BinaryOp::GreaterEqual => "ge",
+        _ => panic!("Unknown condition {}", op.symbol()),
+    }
+}
+
+struct X86 {
+    lines: Vec<String>,
+}
+
+impl X86 {
+    fn code(&mut self, code: String) {
+        self.lines.push(format!("    {}", code));
+    }
+
+    fn directive(&mut self, directive: &str) {
+        self.lines.push(format!("    {}", directive));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(format!("{}:", label));
+    }
+
+    // Works out `eax op right` into eax
+    fn calculate(&mut self, op: BinaryOp, right: &Value) {
+        let right = operand(right);
+        match op {
+            BinaryOp::Add => self.code(format!("addl {}, %eax", right)),
+            BinaryOp::Sub => self.code(format!("subl {}, %eax", right)),
+            BinaryOp::Mul => self.code(format!("imull {}, %eax", right)),
+            BinaryOp::Xor => self.code(format!("xorl {}, %eax", right)),
+            BinaryOp::And => self.code(format!("andl {}, %eax", right)),
+            BinaryOp::Or  => self.code(format!("orl {}, %eax", right)),
+            BinaryOp::Div | BinaryOp::Mod => {
+                self.code(format!("movl {}, %ecx", right));
+                self.code(format!("call {}", if op == BinaryOp::Div { "basic_divide" } else { "basic_modulo" }));
+            }
+            BinaryOp::Shl => {
+                self.code(format!("movl {}, %ecx", right));
+                self.code("shll %cl, %eax".to_string());
+            }
+            _ => {
+                // A comparison leaves -1 (true) or 0 (false), like
+                // traditional BASIC
+                self.code(format!("cmpl {}, %eax", right));
+                self.code(format!("set{} %al", condition(op)));
+                self.code("movzbl %al, %eax".to_string());
+                self.code("negl %eax".to_string());
+            }
+        }
+    }
+}
+
+impl Backend for X86 {
+    fn label(&mut self, block: BlockId) {
+        self.label(&block_label(block));
+    }
+
+    fn copy(&mut self, dest: &Value, src: &Value) {
+        match src {
+            Value::Const(_) => self.code(format!("movl {}, {}", operand(src), operand(dest))),
+            _ => {
+                self.code(format!("movl {}, %eax", operand(src)));
+                self.code(format!("movl %eax, {}", operand(dest)));
+            }
+        }
+    }
+
+    fn arithmetic(&mut self, op: BinaryOp, dest: &Value, left: &Value, right: &Value) {
+        self.code(format!("movl {}, %eax", operand(left)));
+        self.calculate(op, right);
+        self.code(format!("movl %eax, {}", operand(dest)));
+    }
+
+    fn print(&mut self, value: &Value) {
+        self.code(format!("movl {}, %edi", operand(value)));
+        self.code("call basic_print".to_string());
+    }
+
+    fn input(&mut self, dest: &Value) {
+        self.code("call basic_input".to_string());
+        self.code(format!("movl %eax, {}", operand(dest)));
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.lines.push(format!("    # {}", text).trim_end().to_string());
+    }
+
+    fn jump(&mut self, target: BlockId) {
+        self.code(format!("jmp {}", block_label(target)));
+    }
+
+    fn branch(&mut self, op: BinaryOp, left: &Value, right: &Value, target: BlockId) {
+        self.code(format!("movl {}, %eax", operand(left)));
+        self.code(format!("cmpl {}, %eax", operand(right)));
+        self.code(format!("j{} {}", condition(op), block_label(target)));
+    }
+
+    fn call(&mut self, target: BlockId, _after: BlockId) {
+        self.code(format!("cmpl ${}, basic_depth(%rip)", GOSUB_DEPTH));
+        self.code("je .Ltoo_deep".to_string());
+        self.code("incl basic_depth(%rip)".to_string());
+        self.code(format!("call {}", block_label(target)));
+    }
+
+    fn ret(&mut self) {
+        self.code("cmpl $0, basic_depth(%rip)".to_string());
+        self.code("je .Lreturn_without_call".to_string());
+        self.code("decl basic_depth(%rip)".to_string());
+        self.code("ret".to_string());
+    }
+
+    fn halt(&mut self) {
+        self.code("jmp .Lend".to_string());
+    }
+}
+
+// How many gosubs can be running at once, as in C
+const GOSUB_DEPTH: u32 = 65536;
+
+// basic_print writes edi as a decimal number and a newline. basic_input
+// reads a number into eax the same way the emulator does: anything up to
+// the next digit or `-` is skipped, the number ends at the first character
+// that isn't a digit, and at the end of the input it reads 0. Both go to the
+// kernel directly, so the program needs no C library. basic_divide and
+// basic_modulo work out eax / ecx and eax % ecx into eax, where idiv would
+// trap on dividing the lowest number by -1. basic_fail writes the message
+// at rsi, edx bytes long, to stderr and exits with 1.
+const RUNTIME: &str = "
+basic_print:
+    movq %rsp, %rsi
+    subq $32, %rsp
+    decq %rsi
+    movb $10, (%rsi)
+    movslq %edi, %rax
+    movq %rax, %r8
+    testq %rax, %rax
+    jns .Lprint_digits
+    negq %rax
+.Lprint_digits:
+    movl $10, %ecx
+    xorl %edx, %edx
+    divq %rcx
+    addb $48, %dl
+    decq %rsi
+    movb %dl, (%rsi)
+    testq %rax, %rax
+    jnz .Lprint_digits
+    testq %r8, %r8
+    jns .Lprint_write
+    decq %rsi
+    movb $45, (%rsi)
+.Lprint_write:
+    leaq 32(%rsp), %rdx
+    subq %rsi, %rdx
+    movl $1, %eax
+    movl $1, %edi
+    syscall
+    addq $32, %rsp
+    ret
+
+basic_input:
+    pushq %rbx
+    pushq %r12
+    subq $8, %rsp
+    xorl %ebx, %ebx
+.Linput_skip:
+    xorl %r12d, %r12d
+.Linput_sign:
+    call basic_getc
+    cmpl $-1, %eax
+    je .Linput_done
+    cmpl $45, %eax
+    jne .Linput_first
+    movl $1, %r12d
+    jmp .Linput_sign
+.Linput_first:
+    subl $48, %eax
+    cmpl $9, %eax
+    ja .Linput_skip
+.Linput_digit:
+    imull $10, %ebx, %ebx
+    addl %eax, %ebx
+    call basic_getc
+    subl $48, %eax
+    cmpl $9, %eax
+    jbe .Linput_digit
+.Linput_done:
+    movl %ebx, %eax
+    testl %r12d, %r12d
+    jz .Linput_return
+    negl %eax
+.Linput_return:
+    addq $8, %rsp
+    popq %r12
+    popq %rbx
+    ret
+
+basic_getc:
+    subq $8, %rsp
+    xorl %eax, %eax
+    xorl %edi, %edi
+    movq %rsp, %rsi
+    movl $1, %edx
+    syscall
+    cmpq $1, %rax
+    jne .Lgetc_end
+    movzbl (%rsp), %eax
+    addq $8, %rsp
+    ret
+.Lgetc_end:
+    movl $-1, %eax
+    addq $8, %rsp
+    ret
+
+basic_divide:
+    testl %ecx, %ecx
+    jz .Ldivision_by_zero
+    cmpl $-1, %ecx
+    je .Ldivide_negate
+    cltd
+    idivl %ecx
+    ret
+.Ldivide_negate:
+    negl %eax
+    ret
+
+basic_modulo:
+    testl %ecx, %ecx
+    jz .Ldivision_by_zero
+    cmpl $-1, %ecx
+    je .Lmodulo_zero
+    cltd
+    idivl %ecx
+    movl %edx, %eax
+    ret
+.Lmodulo_zero:
+    xorl %eax, %eax
+    ret
+
+.Ldivision_by_zero:
+    leaq .Ldivision_message(%rip), %rsi
+    movl $.Ldivision_end - .Ldivision_message, %edx
+    jmp basic_fail
+.Lreturn_without_call:
+    leaq .Lreturn_message(%rip), %rsi
+    movl $.Lreturn_end - .Lreturn_message, %edx
+    jmp basic_fail
+.Ltoo_deep:
+    leaq .Ldeep_message(%rip), %rsi
+    movl $.Ldeep_end - .Ldeep_message, %edx
+    jmp basic_fail
+
+basic_fail:
+    movl $1, %eax
+    movl $2, %edi
+    syscall
+    movl $60, %eax
+    movl $1, %edi
+    syscall
+
+    .section .rodata
+.Ldivision_message:
+    .ascii \"runtime error: Division by zero\\n\"
+.Ldivision_end:
+.Lreturn_message:
+    .ascii \"runtime error: Return without a call\\n\"
+.Lreturn_end:
+.Ldeep_message:
+    .ascii \"runtime error: Too many nested gosubs\\n\"
+.Ldeep_end:
+
+    .bss
+    .p2align 2
+basic_depth:
+    .zero 4
+";